@@ -0,0 +1,571 @@
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::select;
+use tokio::sync::mpsc::Receiver;
+use tokio::time::Instant;
+use tracing::{debug, info, trace, warn};
+use crate::listener::{validate_depth, wait_until, GatewayClient, QueryError};
+use crate::stats::Stats;
+use crate::transport::{TungsteniteTransport, WsTransport};
+use crate::model::{BookDepthResponse, MarketLiquidityResponse, OrderBook, ProductId, StreamResponseType, UpdateResult, ValidationMode, CLEAR_SCREEN};
+
+/// Receives the book after every applied update (and after invalid updates in `ValidationMode::Lenient`).
+/// Closures taking `(&OrderBook, UpdateResult)` implement it too.
+pub trait OrderBookObserver {
+    fn on_update(&mut self, order_book: &OrderBook, result: UpdateResult);
+
+    /// The stream reconnected: the book is stale until the resnapshot that follows lands (the next `on_update`)
+    fn on_reconnect(&mut self) {}
+
+    /// Called once, just before the first `on_update` where `OrderBook::is_ready` holds: the warm-up is over
+    fn on_ready(&mut self, _order_book: &OrderBook) {}
+
+    /// When to call `on_flush` if no update arrives before then, e.g. to draw the end of a burst the observer
+    /// skipped.  Checked before waiting for each event; None (the default) never calls it.
+    fn flush_deadline(&self) -> Option<Instant> {
+        None
+    }
+
+    fn on_flush(&mut self, _order_book: &OrderBook) {}
+}
+
+impl<F: FnMut(&OrderBook, UpdateResult)> OrderBookObserver for F {
+    fn on_update(&mut self, order_book: &OrderBook, result: UpdateResult) {
+        self(order_book, result)
+    }
+}
+
+/// Redraws the top `levels` levels of the book in place on stdout, at most once per `render_interval`:
+/// a burst of updates is coalesced into one redraw, and the latest book is drawn once the interval is up.
+pub struct PrintObserver {
+    levels: usize,
+    title: Option<String>, // printed above the book, e.g. the product's symbol
+    render_interval: Duration,
+    next_render: Option<Instant>, // None until the first redraw
+    stale: bool,                  // the book changed since the last redraw
+}
+
+impl PrintObserver {
+    /// A zero `render_interval` redraws on every update
+    pub fn new(levels: usize, title: Option<String>, render_interval: Duration) -> Self {
+        PrintObserver {
+            levels,
+            title,
+            render_interval,
+            next_render: None,
+            stale: false,
+        }
+    }
+
+    fn render(&mut self, order_book: &OrderBook) {
+        let title = self.title.as_deref().map(|title| format!("{}\n", title)).unwrap_or_default();
+        print!("{}{}{}", CLEAR_SCREEN, title, order_book.visualize_depth(self.levels));
+        self.next_render = Some(Instant::now() + self.render_interval);
+        self.stale = false;
+    }
+}
+
+impl OrderBookObserver for PrintObserver {
+    fn on_update(&mut self, order_book: &OrderBook, _result: UpdateResult) {
+        match self.next_render {
+            Some(next_render) if Instant::now() < next_render => self.stale = true, // drawn by `on_flush`
+            _ => self.render(order_book),
+        }
+    }
+
+    fn flush_deadline(&self) -> Option<Instant> {
+        self.next_render.filter(|_| self.stale)
+    }
+
+    fn on_flush(&mut self, order_book: &OrderBook) {
+        self.render(order_book);
+    }
+}
+
+/// Writes every update to stdout as one JSON line (see `OrderBook::visualize_json`)
+pub struct JsonObserver {
+    pub levels: usize,
+}
+
+impl OrderBookObserver for JsonObserver {
+    fn on_update(&mut self, order_book: &OrderBook, _result: UpdateResult) {
+        println!("{}", order_book.visualize_json(self.levels));
+    }
+}
+
+/// Ignores every update, for running the builder headless
+pub struct NoopObserver;
+
+impl OrderBookObserver for NoopObserver {
+    fn on_update(&mut self, _order_book: &OrderBook, _result: UpdateResult) {}
+}
+
+/// What `OrderBookBuilder` does when it falls behind and the event channel fills up
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BackpressurePolicy {
+    // leave it to the channel: `Subscribe` waits for room, so nothing is lost but reading the socket stalls
+    #[default]
+    Block,
+    // discard the older half of the queued events; the gap that leaves triggers a resnapshot
+    DropOldest,
+    // discard every queued event and resnapshot straight away
+    Resnapshot,
+}
+
+impl FromStr for BackpressurePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "block" => Ok(BackpressurePolicy::Block),
+            "drop_oldest" => Ok(BackpressurePolicy::DropOldest),
+            "resnapshot" => Ok(BackpressurePolicy::Resnapshot),
+            _ => Err(format!("unknown backpressure policy {}", s)),
+        }
+    }
+}
+
+/// The last `capacity` events the builder received, oldest first, for post-mortem debugging: dumped to the log
+/// whenever the book goes wrong (a gap, a malformed update or a failed validation), so the events around a
+/// corruption are on hand without running with trace logging.  A capacity of 0 keeps nothing.
+#[derive(Debug, Default)]
+pub struct RecentEvents {
+    events: VecDeque<StreamResponseType>,
+    capacity: usize,
+    overwritten: u64, // events pushed out to make room for newer ones
+}
+
+impl RecentEvents {
+    pub fn new(capacity: usize) -> Self {
+        RecentEvents {
+            events: VecDeque::with_capacity(capacity),
+            capacity,
+            overwritten: 0,
+        }
+    }
+
+    /// Oldest first
+    pub fn iter(&self) -> impl Iterator<Item = &StreamResponseType> + '_ {
+        self.events.iter()
+    }
+
+    /// How many events have been pushed out of the buffer to make room, i.e. how much history a dump is missing
+    pub fn overwritten(&self) -> u64 {
+        self.overwritten
+    }
+
+    // Cloned only when enabled, so a disabled buffer costs nothing per event
+    fn record(&mut self, event: &StreamResponseType) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.events.len() == self.capacity {
+            self.events.pop_front();
+            self.overwritten += 1;
+        }
+        self.events.push_back(event.clone());
+    }
+
+    fn dump(&self, reason: &str) {
+        if self.capacity == 0 {
+            return;
+        }
+        warn!(reason, events = self.events.len(), overwritten = self.overwritten, "dumping recent events");
+        for (age, event) in self.events.iter().rev().enumerate() {
+            warn!(age, ?event, "recent event"); // age 0 is the newest
+        }
+    }
+}
+
+/// Maintains a local `OrderBook` from a book_depth stream + market liquidity snapshots.
+/// Every applied update is handed to the caller; drawing it is up to them.
+pub struct OrderBookBuilder<T: WsTransport = TungsteniteTransport> {
+    gateway: GatewayClient<T>,
+    order_book: OrderBook,
+    product_id: ProductId,
+    depth: usize, // how deep to fill the order book up from snapshot (max 100)
+    validation_mode: ValidationMode,
+    backpressure: BackpressurePolicy,
+    recent_events: RecentEvents,
+    stats: Arc<Stats>,
+}
+
+impl<T: WsTransport> OrderBookBuilder<T> {
+    /// Fails if `depth` isn't a valid market liquidity depth (1..=100)
+    pub fn new(
+        gateway: GatewayClient<T>,
+        mut order_book: OrderBook,
+        product_id: ProductId,
+        depth: usize,
+        validation_mode: ValidationMode,
+    ) -> Result<Self, QueryError> {
+        validate_depth(depth)?;
+        order_book.set_product_id(Some(product_id));
+        Ok(OrderBookBuilder {
+            gateway,
+            order_book,
+            product_id,
+            depth,
+            validation_mode,
+            backpressure: BackpressurePolicy::default(),
+            recent_events: RecentEvents::default(),
+            stats: Arc::default(),
+        })
+    }
+
+    /// What to do once the event channel is full, see `BackpressurePolicy`.  Defaults to `Block`.
+    pub fn set_backpressure(&mut self, policy: BackpressurePolicy) {
+        self.backpressure = policy;
+    }
+
+    /// Keep the last `capacity` received events and log them when the book goes wrong, see `RecentEvents`.
+    /// Defaults to 0, which keeps none.  Replaces any events kept so far.
+    pub fn set_recent_events(&mut self, capacity: usize) {
+        self.recent_events = RecentEvents::new(capacity);
+    }
+
+    pub fn recent_events(&self) -> &RecentEvents {
+        &self.recent_events
+    }
+
+    pub fn order_book(&self) -> &OrderBook {
+        &self.order_book
+    }
+
+    /// Counters for this builder; hand a clone to `Subscribe` so parse errors are counted too
+    pub fn stats(&self) -> Arc<Stats> {
+        self.stats.clone()
+    }
+
+    /// Consume book_depth events from `receiver`, handing the book to `observer` after every applied update.
+    /// Events for products other than the builder's are dropped.
+    #[tracing::instrument(name = "order_book", skip_all, fields(product_id = %self.product_id))]
+    pub async fn run<O>(&mut self, mut receiver: Receiver<StreamResponseType>, mut observer: O)
+    where
+        O: OrderBookObserver,
+    {
+        // From the docs: https://docs.vertexprotocol.com/developer-resources/api/subscriptions/events#book-depth
+        //
+        // To keep an updated local orderbook, do the following:
+        // 1. Subscribe to the book_depth stream and queue up events.
+        // 2. Get a market data snapshot by calling MarketLiquidity. The snapshot contains a timestamp in the response
+        // 3. Apply events with max_timestamp > snapshot timestamp.
+        // 4. When you receive an event where its last_max_timestamp is not equal to the last event you've received,
+        //    it means some events were lost and you should repeat 1-3 again.
+        //
+        // The caller has already subscribed (1).  While a snapshot is in flight, events keep being read off
+        // `receiver` into `queue` (see `load_snapshot`), and the queue is drained before reading any further
+        // events, so nothing that arrives during the round-trip is applied out of order (3).
+
+        let mut queue = VecDeque::new();
+        let mut warmed_up = false;
+        self.load_snapshot(&mut receiver, &mut queue, &mut observer).await;
+
+        loop {
+            if self.backpressure != BackpressurePolicy::Block && is_full(&receiver) {
+                self.shed_load(&mut receiver, &mut queue, &mut observer).await;
+            }
+
+            let data = match queue.pop_front() {
+                Some(data) => data,
+                None => {
+                    let event = select! {
+                        event = receiver.recv() => match event {
+                            Some(event) => event,
+                            None => break,
+                        },
+                        _ = wait_until(observer.flush_deadline()) => {
+                            observer.on_flush(&self.order_book);
+                            continue;
+                        }
+                    };
+                    self.recent_events.record(&event);
+                    match event {
+                        StreamResponseType::BookDepth(data) if data.product_id == self.product_id => data,
+                        StreamResponseType::BookDepth(data) => {
+                            skip_other_product(&self.stats, &data);
+                            continue;
+                        }
+                        StreamResponseType::Reconnected => {
+                            // whatever was sent while disconnected is lost; don't wait for a gap to notice
+                            observer.on_reconnect();
+                            self.stats.record_resnapshot();
+                            info!("stream reconnected, retrieving snapshot");
+                            self.load_snapshot(&mut receiver, &mut queue, &mut observer).await;
+                            continue;
+                        }
+                        _ => continue,
+                    }
+                }
+            };
+
+            match self.order_book.update(data) {
+                Ok(UpdateResult::Applied) => {
+                    self.stats.record_update_applied();
+                    trace!(last_update_ts = self.order_book.last_update_ts(), "applied book depth update");
+                    if !warmed_up && self.order_book.is_ready() {
+                        warmed_up = true;
+                        info!("order book ready");
+                        observer.on_ready(&self.order_book);
+                    }
+                    observer.on_update(&self.order_book, UpdateResult::Applied);
+                }
+                Ok(UpdateResult::Stale) => self.stats.record_update_dropped(), // drop msgs from before the snapshot
+                Ok(UpdateResult::GapDetected { expected, got }) => {
+                    self.stats.record_gap();
+                    self.stats.record_resnapshot();
+                    info!(expected, got, "dropped a book depth update, retrieving snapshot");
+                    self.recent_events.dump("gap detected");
+                    self.load_snapshot(&mut receiver, &mut queue, &mut observer).await;
+                }
+                // malformed event: nothing was applied, but its changes are missing from the book now.  Resnapshot
+                // rather than rely on the next event's gap check, which doesn't run on the first event after a snapshot.
+                Err(e) if e.is_malformed_update() => {
+                    self.stats.record_update_dropped();
+                    self.stats.record_resnapshot();
+                    warn!(error = %e, "skipping malformed book depth update, retrieving snapshot");
+                    self.recent_events.dump("malformed update");
+                    self.load_snapshot(&mut receiver, &mut queue, &mut observer).await;
+                }
+                Err(e) => match self.validation_mode {
+                    ValidationMode::Strict => {
+                        warn!(error = %e, "invalid order book, retrieving snapshot");
+                        self.recent_events.dump("invalid order book");
+                        self.stats.record_resnapshot();
+                        self.load_snapshot(&mut receiver, &mut queue, &mut observer).await;
+                    }
+                    ValidationMode::Lenient => {
+                        warn!(error = %e, "invalid order book");
+                        self.recent_events.dump("invalid order book");
+                        observer.on_update(&self.order_book, UpdateResult::Applied); // applied, but failed validation
+                    }
+                },
+            }
+        }
+
+        // the stream ended mid-burst: hand over the final book rather than leave it unflushed
+        if observer.flush_deadline().is_some() {
+            observer.on_flush(&self.order_book);
+        }
+    }
+
+    // The channel filled up: discard queued events according to `self.backpressure`.
+    // Any book_depth event discarded leaves a gap, so the book is resnapshotted either way.
+    async fn shed_load<O: OrderBookObserver>(
+        &mut self,
+        receiver: &mut Receiver<StreamResponseType>,
+        queue: &mut VecDeque<BookDepthResponse>,
+        observer: &mut O,
+    ) {
+        let discard = match self.backpressure {
+            BackpressurePolicy::Block => return,
+            BackpressurePolicy::DropOldest => (receiver.len() / 2).max(1),
+            BackpressurePolicy::Resnapshot => receiver.len(),
+        };
+        let mut reconnected = false;
+        for _ in 0..discard {
+            match receiver.try_recv() {
+                Ok(StreamResponseType::BookDepth(_)) => self.stats.record_update_dropped(),
+                Ok(StreamResponseType::Reconnected) => reconnected = true,
+                Ok(_) => {}
+                Err(_) => break,
+            }
+        }
+        warn!(discarded = discard, policy = ?self.backpressure, "event channel full, discarding queued events");
+        if reconnected {
+            observer.on_reconnect(); // still owed to the observer, though the resnapshot below covers the book
+        }
+
+        if self.backpressure == BackpressurePolicy::Resnapshot {
+            for _ in queue.drain(..) {
+                self.stats.record_update_dropped();
+            }
+            self.stats.record_resnapshot();
+            self.load_snapshot(receiver, queue, observer).await;
+        }
+    }
+
+    // Repopulate the book from a fresh snapshot, queueing book_depth events that arrive in the meantime.
+    // Events already in `queue` are kept: they predate the new ones, and `update` drops whatever the snapshot covers.
+    async fn load_snapshot<O: OrderBookObserver>(
+        &mut self,
+        receiver: &mut Receiver<StreamResponseType>,
+        queue: &mut VecDeque<BookDepthResponse>,
+        observer: &mut O,
+    ) {
+        loop {
+            let mut reconnected = false;
+            let query = query_market_liquidity(&mut self.gateway, self.product_id, self.depth);
+            tokio::pin!(query);
+            let snapshot = loop {
+                select! {
+                    snapshot = &mut query => break snapshot,
+                    Some(event) = receiver.recv() => {
+                        self.recent_events.record(&event);
+                        match event {
+                            StreamResponseType::BookDepth(data) if data.product_id == self.product_id => queue.push_back(data),
+                            StreamResponseType::BookDepth(data) => skip_other_product(&self.stats, &data),
+                            StreamResponseType::Reconnected => reconnected = true,
+                            _ => {}
+                        }
+                    }
+                }
+            };
+
+            // the snapshot may have been taken before the disconnect, and events sent during it are lost either way
+            if reconnected {
+                observer.on_reconnect();
+                self.stats.record_resnapshot();
+                info!("stream reconnected while a snapshot was in flight, retrieving another");
+                continue;
+            }
+
+            // an invalid snapshot (bad timestamp, crossed levels) can't be trusted, and a stale one would move the book
+            // backwards; fetch another instead
+            match self.order_book.from_snapshot(snapshot) {
+                Ok(()) => return,
+                Err(e) => {
+                    warn!(error = %e, "invalid snapshot, retrying");
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+            }
+        }
+    }
+}
+
+// A book_depth event for some other product shares the channel (several products on one subscription):
+// applying it would corrupt this book
+fn skip_other_product(stats: &Stats, data: &BookDepthResponse) {
+    stats.record_update_dropped();
+    debug!(product_id = %data.product_id, "dropping book depth update for another product");
+}
+
+fn is_full(receiver: &Receiver<StreamResponseType>) -> bool {
+    receiver.len() >= receiver.max_capacity()
+}
+
+// The book is useless without a snapshot, so keep trying even after the client gives up
+pub(crate) async fn query_market_liquidity<T: WsTransport>(gateway: &mut GatewayClient<T>, product_id: ProductId, depth: usize) -> MarketLiquidityResponse {
+    loop {
+        match gateway.query_market_liquidity(product_id, depth).await {
+            Ok(resp) => return resp,
+            Err(e) => {
+                warn!(error = %e, "failed to query market liquidity, retrying");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::listener::{subscribe_with_transport, Subscriptions};
+    use crate::transport::MockTransport;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    fn book_depth(last_max_timestamp: u32, max_timestamp: u32, bid: (u32, u32)) -> String {
+        format!(
+            r#"{{"type":"book_depth","min_timestamp":"{}","max_timestamp":"{}","last_max_timestamp":"{}","product_id":2,"bids":[["{}","{}"]],"asks":[]}}"#,
+            last_max_timestamp + 1, max_timestamp, last_max_timestamp, bid.0, bid.1
+        )
+    }
+
+    fn snapshot(timestamp: u32, bid: (u32, u32)) -> String {
+        format!(
+            r#"{{"status":"success","data":{{"bids":[["{}","{}"]],"asks":[["101","1"]],"timestamp":"{}"}},"request_type":"query_market_liquidity"}}"#,
+            bid.0, bid.1, timestamp
+        )
+    }
+
+    #[tokio::test]
+    async fn gap_in_last_max_timestamp_triggers_a_resnapshot() {
+        let config = Config { max_consecutive_failures: Some(1), ..Config::default() }; // stop once the script ends
+        let stream = [
+            book_depth(1, 2, (99, 2)),
+            book_depth(3, 4, (98, 1)), // expected last_max_timestamp 2: the 2..3 event was lost
+            book_depth(4, 5, (97, 5)), // covered by the second snapshot
+            book_depth(5, 6, (96, 1)),
+        ];
+        let subscription = MockTransport::from_text(vec![stream.iter().map(String::as_str).collect()]);
+        let (first, second) = (snapshot(1, (99, 1)), snapshot(5, (97, 1)));
+        let gateway = MockTransport::from_text(vec![vec![first.as_str(), second.as_str()]]);
+
+        let client = GatewayClient::with_transport(gateway.clone(), "ws://gateway", config.backoff, 0, Duration::from_secs(1), 0.0);
+        let mut builder = OrderBookBuilder::new(client, OrderBook::new(18, 18).unwrap(), ProductId(2), 10, ValidationMode::Strict).unwrap();
+        let (sender, receiver) = tokio::sync::mpsc::channel(10);
+        let mut applied = Vec::new();
+        let stream = subscribe_with_transport(&subscription, sender, Subscriptions::new(vec![]), &config, None, builder.stats());
+        let (result, _) = tokio::join!(
+            stream,
+            builder.run(receiver, |book: &OrderBook, _: UpdateResult| applied.push(book.last_update_ts()))
+        );
+
+        assert!(result.is_err()); // gave up reconnecting after the script ran out
+        assert_eq!(applied, vec![2, 6]);
+        assert_eq!(gateway.sent().len(), 2); // the initial snapshot and the resnapshot
+        let stats = builder.stats().snapshot();
+        assert_eq!((stats.gaps_detected, stats.resnapshots), (1, 1));
+        assert_eq!(builder.order_book().best_bid(), Some((97, 1)));
+        assert_eq!(builder.order_book().num_bid_levels(), 2);
+    }
+
+    #[tokio::test]
+    async fn malformed_first_event_after_a_snapshot_triggers_a_resnapshot() {
+        let config = Config::default();
+        let (first, second) = (snapshot(1, (99, 1)), snapshot(3, (98, 2)));
+        let gateway = MockTransport::from_text(vec![vec![first.as_str(), second.as_str()]]);
+        let client = GatewayClient::with_transport(gateway.clone(), "ws://gateway", config.backoff, 0, Duration::from_secs(1), 0.0);
+        let mut builder = OrderBookBuilder::new(client, OrderBook::new(18, 18).unwrap(), ProductId(2), 10, ValidationMode::Strict).unwrap();
+
+        let (sender, receiver) = tokio::sync::mpsc::channel(10);
+        let malformed = BookDepthResponse::new(1, 2, ProductId(2), vec![(98, 1), (98, 2)], vec![]); // price listed twice
+        sender.send(StreamResponseType::BookDepth(malformed)).await.unwrap();
+        let next = BookDepthResponse::new(2, 3, ProductId(2), vec![(97, 1)], vec![]); // chains onto the dropped event
+        sender.send(StreamResponseType::BookDepth(next)).await.unwrap();
+        drop(sender);
+        builder.run(receiver, NoopObserver).await;
+
+        assert_eq!(gateway.sent().len(), 2); // the initial snapshot and the resnapshot
+        assert_eq!(builder.stats().snapshot().resnapshots, 1);
+        assert_eq!(builder.order_book().best_bid(), Some((98, 2))); // from the resnapshot, which covers both events
+        assert_eq!(builder.order_book().num_bid_levels(), 1);
+    }
+
+    struct CountReconnects(Rc<Cell<usize>>);
+
+    impl OrderBookObserver for CountReconnects {
+        fn on_update(&mut self, _order_book: &OrderBook, _result: UpdateResult) {}
+
+        fn on_reconnect(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn reconnect_while_a_snapshot_is_in_flight_triggers_another() {
+        let mut config = Config::default();
+        config.backoff.base = Duration::from_millis(10);
+        let (first, second) = (snapshot(1, (99, 1)), snapshot(5, (98, 2)));
+        // the first attempt finds the socket closed, so the query is still in flight when the reconnect is read
+        let gateway = MockTransport::from_text(vec![vec![], vec![first.as_str(), second.as_str()]]);
+        let client = GatewayClient::with_transport(gateway.clone(), "ws://gateway", config.backoff, 1, Duration::from_secs(1), 0.0);
+        let mut builder = OrderBookBuilder::new(client, OrderBook::new(18, 18).unwrap(), ProductId(2), 10, ValidationMode::Strict).unwrap();
+
+        let (sender, receiver) = tokio::sync::mpsc::channel(10);
+        sender.send(StreamResponseType::Reconnected).await.unwrap();
+        drop(sender);
+        let reconnects = Rc::new(Cell::new(0));
+        builder.run(receiver, CountReconnects(reconnects.clone())).await;
+
+        assert_eq!(reconnects.get(), 1);
+        assert_eq!(gateway.sent().len(), 3); // the failed attempt, the snapshot from before the reconnect, and another
+        assert_eq!(builder.stats().snapshot().resnapshots, 1);
+        assert_eq!(builder.order_book().last_update_ts(), 5);
+        assert_eq!(builder.order_book().best_bid(), Some((98, 2)));
+    }
+}
@@ -0,0 +1,312 @@
+use std::env;
+use std::fmt;
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use ethers::addressbook::Address;
+use ethers::prelude::U256;
+use ethers_core::types::transaction::eip712::EIP712Domain;
+use tokio_tungstenite::tungstenite::extensions::DeflateConfig;
+use tokio_tungstenite::tungstenite::http::Uri;
+use crate::builder::BackpressurePolicy;
+use crate::listener::BackoffConfig;
+use crate::model::ProductId;
+use crate::proxy::proxy_from_env;
+use crate::signing::SigningDomain;
+
+const PRODUCT_ID: ProductId = ProductId(2); // BTC-USDC perp
+// Events queued between `Subscribe` and the order book builder.  tokio allocates the queue as it fills, so an idle
+// 1MM buffer costs next to nothing, but a full one holds a million book_depth events: hundreds of MB, and a book
+// minutes behind the market.  Pair a smaller buffer with a `BackpressurePolicy` other than `Block` to bound both.
+const BOOK_DEPTH_STREAM_BUFFER_SIZE: usize = 1000000; // 1MM
+const MARKET_LIQ_QUERY_DEPTH: usize = 10; // how deep to fill the order book up from snapshot (max 100)
+const PING_FRAME_INTERVAL: u64 = 5; // how often to send ping frames to keep the ws connection alive
+const PING_FRAME_INTERVALS: RangeInclusive<u64> = 1..=30; // the gateway closes connections that don't ping for 30s
+const QUERY_MAX_RETRIES: usize = 5; // transient failures retried per gateway query
+const QUERY_TIMEOUT: u64 = 5; // seconds to wait for a gateway reply before retrying
+const AUTH_EXPIRY: u64 = 30; // seconds a signed stream authentication stays valid
+const RENDER_INTERVAL: u64 = 100; // ms between redraws of the table; bursts of updates are coalesced
+// Gateway queries per second.  Vertex limits queries per IP (https://docs.vertexprotocol.com/developer-resources/api/rate-limits);
+// this stays well under that even when resnapshots burst during choppy markets.
+const QUERY_RATE_LIMIT: f64 = 10.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Network {
+    #[default]
+    Mainnet, // Arbitrum One
+    Testnet, // Arbitrum Sepolia
+}
+
+impl Network {
+    pub fn subscription_url(&self) -> &'static str {
+        match self {
+            Network::Mainnet => "wss://gateway.prod.vertexprotocol.com/v1/subscribe",
+            Network::Testnet => "wss://gateway.sepolia-test.vertexprotocol.com/v1/subscribe",
+        }
+    }
+
+    pub fn gateway_url(&self) -> &'static str {
+        match self {
+            Network::Mainnet => "wss://gateway.prod.vertexprotocol.com/v1/ws",
+            Network::Testnet => "wss://gateway.sepolia-test.vertexprotocol.com/v1/ws",
+        }
+    }
+
+    pub fn chain_id(&self) -> u64 {
+        match self {
+            Network::Mainnet => 42161,
+            Network::Testnet => 421614,
+        }
+    }
+
+    // Vertex endpoint contract that verifies signed payloads
+    pub fn verifying_contract(&self) -> Address {
+        let address = match self {
+            Network::Mainnet => "0xbbEE07B3e8121227AfCFe1E2B82772246226128e",
+            Network::Testnet => "0xaDeFDE1A14B6ba4DA3e82414209408a49930E8DC",
+        };
+        Address::from_str(address).expect("Invalid address")
+    }
+
+    pub fn eip712_domain(&self) -> EIP712Domain {
+        EIP712Domain {
+            name: Some("Vertex".to_string()),
+            version: Some("0.0.1".to_string()),
+            chain_id: Some(U256::from(self.chain_id())),
+            verifying_contract: Some(self.verifying_contract()),
+            salt: None,
+        }
+    }
+
+    /// `eip712_domain` with its separator, built once per network and shared by every stream authentication
+    pub fn signing_domain(&self) -> &'static SigningDomain {
+        static MAINNET: OnceLock<SigningDomain> = OnceLock::new();
+        static TESTNET: OnceLock<SigningDomain> = OnceLock::new();
+        let cell = match self {
+            Network::Mainnet => &MAINNET,
+            Network::Testnet => &TESTNET,
+        };
+        cell.get_or_init(|| SigningDomain::new(self.eip712_domain()))
+    }
+
+    /// Domain orders are signed under: the same as `eip712_domain`, but verified against
+    /// `address(product_id)` rather than the endpoint contract
+    pub fn order_domain(&self, product_id: ProductId) -> EIP712Domain {
+        EIP712Domain {
+            verifying_contract: Some(Address::from_low_u64_be(product_id.0 as u64)),
+            ..self.eip712_domain()
+        }
+    }
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "mainnet" => Ok(Network::Mainnet),
+            "testnet" => Ok(Network::Testnet),
+            _ => Err(format!("unknown network {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub network: Network,
+    pub product_id: ProductId,
+    pub subscription_url: String,
+    pub gateway_url: String,
+    pub market_liq_query_depth: usize,
+    pub ping_frame_interval: u64, // seconds, 1 to 30; the connection is considered dead after 3 intervals without traffic
+    pub book_depth_stream_buffer_size: usize,
+    pub backpressure: BackpressurePolicy, // what the order book builder does once that buffer is full
+    pub recent_events: usize, // events the order book builder keeps to log when the book goes wrong, 0 keeps none
+    pub render_interval: u64, // ms; the table is redrawn at most this often, 0 redraws on every update
+    pub query_max_retries: usize,
+    pub query_timeout: u64, // seconds
+    pub query_rate_limit: f64, // gateway queries per second, shared by every query on a client
+    pub auth_expiry: u64, // seconds; stream authentications are rejected if they reach the gateway later than this
+    pub proxy_url: Option<String>, // http:// or socks5:// proxy to tunnel websockets through
+    pub compression: Option<DeflateConfig>, // permessage-deflate settings; None disables compression (lower latency, more bandwidth)
+    pub backoff: BackoffConfig,
+    pub max_consecutive_failures: Option<usize>, // `Subscribe` gives up after this many failed connections in a row; None retries forever
+    pub record_to: Option<String>, // file every received frame is appended to, for `replay_from`
+    pub bind_address: Option<IpAddr>, // local address to connect from, e.g. to pick the interface on a multi-homed host
+    // Seconds after which `Subscribe` replaces its connection before the server force-closes it: a new connection is
+    // subscribed while the old one keeps delivering, and the new one's frames are held back until its events overlap
+    // what the old one delivered, on every stream and product.  Then the old one is closed and the held frames it
+    // already delivered are dropped, so the switch needs no resnapshot and nothing repeats.  If there's no overlap
+    // within the read timeout (3 ping intervals), it switches anyway and sends `Reconnected`, so the book resnapshots;
+    // a quiet stream (e.g. fills) that delivered earlier but has nothing new makes it wait for that timeout.  Set it
+    // below the server's connection lifetime.  None only reconnects after the server closes the socket.
+    pub rotate_connection_after: Option<u64>,
+}
+
+#[derive(Debug)]
+pub struct ConfigError {
+    pub var: &'static str,
+    pub value: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid value for {}: {:?}", self.var, self.value)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config::for_network(Network::default())
+    }
+}
+
+impl Config {
+    /// Defaults with the subscribe/gateway urls of `network`
+    pub fn for_network(network: Network) -> Self {
+        Config {
+            network,
+            product_id: PRODUCT_ID,
+            subscription_url: network.subscription_url().to_string(),
+            gateway_url: network.gateway_url().to_string(),
+            market_liq_query_depth: MARKET_LIQ_QUERY_DEPTH,
+            ping_frame_interval: PING_FRAME_INTERVAL,
+            book_depth_stream_buffer_size: BOOK_DEPTH_STREAM_BUFFER_SIZE,
+            backpressure: BackpressurePolicy::default(),
+            recent_events: 0,
+            render_interval: RENDER_INTERVAL,
+            query_max_retries: QUERY_MAX_RETRIES,
+            query_timeout: QUERY_TIMEOUT,
+            query_rate_limit: QUERY_RATE_LIMIT,
+            auth_expiry: AUTH_EXPIRY,
+            proxy_url: None,
+            compression: Some(DeflateConfig::default()),
+            backoff: BackoffConfig::default(),
+            max_consecutive_failures: None,
+            record_to: None,
+            bind_address: None,
+            rotate_connection_after: None,
+        }
+    }
+
+    /// Defaults overridden by any of the `VERTEX_*` environment variables that are set.
+    /// `VERTEX_NETWORK` picks the default urls, which the url variables can still override.
+    pub fn from_env() -> Result<Self, ConfigError> {
+        let network = env_or("VERTEX_NETWORK", Network::default())?;
+        let default = Config::for_network(network);
+        Ok(Config {
+            network,
+            product_id: env_or("VERTEX_PRODUCT_ID", default.product_id)?,
+            subscription_url: ws_url_or("VERTEX_SUBSCRIPTION_URL", default.subscription_url)?,
+            gateway_url: ws_url_or("VERTEX_GATEWAY_URL", default.gateway_url)?,
+            market_liq_query_depth: env_or("VERTEX_MARKET_LIQ_QUERY_DEPTH", default.market_liq_query_depth)?,
+            ping_frame_interval: in_range_or("VERTEX_PING_FRAME_INTERVAL", default.ping_frame_interval, PING_FRAME_INTERVALS)?,
+            book_depth_stream_buffer_size: env_or("VERTEX_BOOK_DEPTH_STREAM_BUFFER_SIZE", default.book_depth_stream_buffer_size)?,
+            backpressure: env_or("VERTEX_BACKPRESSURE", default.backpressure)?,
+            recent_events: env_or("VERTEX_RECENT_EVENTS", default.recent_events)?,
+            render_interval: env_or("VERTEX_RENDER_INTERVAL", default.render_interval)?,
+            query_max_retries: env_or("VERTEX_QUERY_MAX_RETRIES", default.query_max_retries)?,
+            query_timeout: env_or("VERTEX_QUERY_TIMEOUT", default.query_timeout)?,
+            query_rate_limit: env_or("VERTEX_QUERY_RATE_LIMIT", default.query_rate_limit)?,
+            auth_expiry: env_or("VERTEX_AUTH_EXPIRY", default.auth_expiry)?,
+            proxy_url: env::var("VERTEX_PROXY_URL").ok().or_else(proxy_from_env),
+            compression: match env_or("VERTEX_COMPRESSION", true)? {
+                true => default.compression,
+                false => None,
+            },
+            backoff: default.backoff,
+            max_consecutive_failures: match env::var("VERTEX_MAX_CONSECUTIVE_FAILURES") {
+                Ok(value) => Some(value.parse().map_err(|_| ConfigError { var: "VERTEX_MAX_CONSECUTIVE_FAILURES", value })?),
+                Err(_) => default.max_consecutive_failures,
+            },
+            record_to: env::var("VERTEX_RECORD_TO").ok(),
+            bind_address: match env::var("VERTEX_BIND_ADDRESS") {
+                Ok(value) => Some(value.parse().map_err(|_| ConfigError { var: "VERTEX_BIND_ADDRESS", value })?),
+                Err(_) => default.bind_address,
+            },
+            rotate_connection_after: match env::var("VERTEX_ROTATE_CONNECTION_AFTER") {
+                Ok(value) => Some(value.parse().map_err(|_| ConfigError { var: "VERTEX_ROTATE_CONNECTION_AFTER", value })?),
+                Err(_) => default.rotate_connection_after,
+            },
+        })
+    }
+}
+
+fn env_or<T: FromStr>(var: &'static str, default: T) -> Result<T, ConfigError> {
+    match env::var(var) {
+        Ok(value) => value.parse().map_err(|_| ConfigError { var, value }),
+        Err(_) => Ok(default),
+    }
+}
+
+// Like `env_or`, but values outside `range` are rejected too, e.g. a 0 interval that would fire continuously
+fn in_range_or(var: &'static str, default: u64, range: RangeInclusive<u64>) -> Result<u64, ConfigError> {
+    match env::var(var) {
+        Ok(value) => match value.parse() {
+            Ok(parsed) if range.contains(&parsed) => Ok(parsed),
+            _ => Err(ConfigError { var, value }),
+        },
+        Err(_) => Ok(default),
+    }
+}
+
+// A url override, e.g. a regional mirror or a local proxy that speaks the Vertex protocol
+fn ws_url_or(var: &'static str, default: String) -> Result<String, ConfigError> {
+    match env::var(var) {
+        Ok(value) if is_ws_url(&value) => Ok(value),
+        Ok(value) => Err(ConfigError { var, value }),
+        Err(_) => Ok(default),
+    }
+}
+
+/// Whether `url` is a well-formed `ws://` or `wss://` url with a host, e.g. `wss://gateway.prod.vertexprotocol.com/v1/ws`
+pub fn is_ws_url(url: &str) -> bool {
+    match url.parse::<Uri>() {
+        Ok(uri) => matches!(uri.scheme_str(), Some("ws") | Some("wss")) && uri.host().is_some_and(|host| !host.is_empty()),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_is_not_a_valid_interval() {
+        // a variable only this test sets, so it can't race other tests reading the real ones
+        env::set_var("VERTEX_TEST_NONZERO_INTERVAL", "0");
+        let error = in_range_or("VERTEX_TEST_NONZERO_INTERVAL", 5, PING_FRAME_INTERVALS).unwrap_err();
+        assert_eq!((error.var, error.value.as_str()), ("VERTEX_TEST_NONZERO_INTERVAL", "0"));
+
+        env::set_var("VERTEX_TEST_NONZERO_INTERVAL", "1");
+        assert_eq!(in_range_or("VERTEX_TEST_NONZERO_INTERVAL", 5, PING_FRAME_INTERVALS).unwrap(), 1);
+        env::remove_var("VERTEX_TEST_NONZERO_INTERVAL");
+        assert_eq!(in_range_or("VERTEX_TEST_NONZERO_INTERVAL", 5, PING_FRAME_INTERVALS).unwrap(), 5);
+    }
+
+    #[test]
+    fn ping_interval_is_at_most_30_seconds() {
+        // a variable only this test sets, as above
+        env::set_var("VERTEX_TEST_PING_INTERVAL", "30");
+        assert_eq!(in_range_or("VERTEX_TEST_PING_INTERVAL", 5, PING_FRAME_INTERVALS).unwrap(), 30);
+        env::set_var("VERTEX_TEST_PING_INTERVAL", "31");
+        let error = in_range_or("VERTEX_TEST_PING_INTERVAL", 5, PING_FRAME_INTERVALS).unwrap_err();
+        assert_eq!((error.var, error.value.as_str()), ("VERTEX_TEST_PING_INTERVAL", "31"));
+        env::remove_var("VERTEX_TEST_PING_INTERVAL");
+    }
+
+    #[test]
+    fn ws_urls_need_a_ws_scheme_and_a_host() {
+        assert!(is_ws_url("wss://gateway.prod.vertexprotocol.com/v1/ws"));
+        assert!(is_ws_url("ws://localhost:8080"));
+        assert!(is_ws_url("ws://[::1]:8080/v1/subscribe"));
+        assert!(!is_ws_url("https://gateway.prod.vertexprotocol.com/v1/ws")); // not a websocket scheme
+        assert!(!is_ws_url("gateway.prod.vertexprotocol.com/v1/ws")); // no scheme
+        assert!(!is_ws_url("wss:///v1/ws")); // no host
+        assert!(!is_ws_url("wss://gateway prod/v1/ws")); // not a url
+        assert!(!is_ws_url(""));
+    }
+}
@@ -0,0 +1,95 @@
+use std::io;
+use tokio_tungstenite::tungstenite;
+use crate::config::ConfigError;
+use crate::listener::{AuthError, OrderError, QueryError, SenderError, SubscribeError};
+use crate::model::OrderBookError;
+
+/// Any failure from this crate.  Each module returns its own error type, which says exactly what went wrong;
+/// they all convert into this one, so a caller can `?` across modules and return a single `Result`.
+/// `kind` tells the failure modes apart without matching every nested variant.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Query(#[from] QueryError),
+    #[error(transparent)]
+    Subscribe(#[from] SubscribeError),
+    #[error(transparent)]
+    Auth(#[from] AuthError),
+    #[error(transparent)]
+    Order(#[from] OrderError),
+    #[error(transparent)]
+    Sender(#[from] SenderError),
+    #[error(transparent)]
+    OrderBook(#[from] OrderBookError),
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+    #[error("Failed to parse JSON: {0}")]
+    Parse(#[from] serde_json::Error),
+    #[error(transparent)]
+    Io(#[from] io::Error), // reading or writing a recording
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Broad class of an `Error`, e.g. to decide whether to retry, alert or give up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    Connection, // couldn't reach the server, or the socket dropped; usually worth retrying
+    Handshake,  // the websocket upgrade failed, e.g. a bad url, a TLS error or a proxy refusing the upgrade
+    Timeout,    // no reply in time
+    Parse,      // a message didn't have the expected shape
+    Validation, // inconsistent input or order book state
+    Auth,       // credentials rejected, or signing failed
+    Rejected,   // the gateway refused a well-formed request
+    Config,
+    Io,
+}
+
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Query(e) => query_kind(e),
+            Error::Subscribe(e) => match e {
+                SubscribeError::TooManyFailures { .. } => ErrorKind::Connection,
+                SubscribeError::Unauthorized { .. } => ErrorKind::Auth,
+                SubscribeError::Authentication(e) => auth_kind(e),
+            },
+            Error::Auth(e) => auth_kind(e),
+            Error::Order(e) => match e {
+                OrderError::Sign(_) => ErrorKind::Auth,
+                OrderError::Query(e) => query_kind(e),
+            },
+            Error::Sender(_) | Error::OrderBook(_) => ErrorKind::Validation,
+            Error::Config(_) => ErrorKind::Config,
+            Error::Parse(_) => ErrorKind::Parse,
+            Error::Io(_) => ErrorKind::Io,
+        }
+    }
+}
+
+fn query_kind(e: &QueryError) -> ErrorKind {
+    match e {
+        QueryError::Connect(e) if is_handshake_error(e) => ErrorKind::Handshake,
+        QueryError::Connect(_) | QueryError::Send(_) | QueryError::Receive(_) | QueryError::Closed => ErrorKind::Connection,
+        QueryError::Timeout(_) => ErrorKind::Timeout,
+        QueryError::InvalidDepth(_) => ErrorKind::Validation,
+        QueryError::Gateway { .. } => ErrorKind::Rejected,
+        QueryError::Parse(_) | QueryError::NonText(_) => ErrorKind::Parse,
+    }
+}
+
+fn auth_kind(e: &AuthError) -> ErrorKind {
+    match e {
+        AuthError::Send(_) | AuthError::Receive(_) | AuthError::Closed => ErrorKind::Connection,
+        AuthError::Parse(_) => ErrorKind::Parse,
+        AuthError::Sign(_) | AuthError::Rejected(_) => ErrorKind::Auth,
+    }
+}
+
+// The TCP connection was made but the TLS or HTTP upgrade on top of it failed
+fn is_handshake_error(e: &tungstenite::Error) -> bool {
+    matches!(
+        e,
+        tungstenite::Error::Tls(_) | tungstenite::Error::Url(_) | tungstenite::Error::Http(_) | tungstenite::Error::HttpFormat(_)
+    )
+}
@@ -0,0 +1,18 @@
+#![allow(non_snake_case)]
+
+pub mod model;
+pub mod listener;
+pub mod builder;
+pub mod config;
+pub mod stats;
+pub mod symbols;
+pub mod proxy;
+pub mod transport;
+pub mod signing;
+pub mod replay;
+pub mod request;
+pub mod router;
+pub mod multibook;
+pub mod error;
+
+pub use error::{Error, ErrorKind, Result};
@@ -1,182 +1,1189 @@
-use std::str::FromStr;
-use ethers_core::types::transaction::eip712::{EIP712Domain, Eip712};
-use ethers::prelude::{LocalWallet, U256};
-use ethers::types::H256;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use ethers::prelude::LocalWallet;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use ethers::addressbook::Address;
-use ethers::prelude::rand::thread_rng;
-use ethers_core::utils::keccak256;
+use ethers::prelude::rand::{thread_rng, Rng};
 use ethers_signers::Signer;
-use futures_util::{SinkExt, StreamExt};
+use futures_util::{SinkExt, Stream, StreamExt};
 use hex::encode;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
 use serde_json::json;
+use tokio::net::TcpStream;
 use tokio::select;
-use tokio::sync::mpsc::Sender;
+use tokio::sync::mpsc::{self, Sender};
+use tokio_stream::wrappers::ReceiverStream;
+use tokio::sync::Notify;
+use tracing::{debug, info, warn};
 use tokio_tungstenite::{
-    connect_async_with_config, tungstenite::extensions::DeflateConfig,
-    tungstenite::protocol::WebSocketConfig, tungstenite::Message,
+    tungstenite, MaybeTlsStream, WebSocketStream, tungstenite::extensions::DeflateConfig, tungstenite::Message,
 };
-use vertex_sdk::eip712_structs::StreamAuthentication;
-use crate::model::{MarketLiquidityResponse, StreamResponseType};
-use crate::PING_FRAME_INTERVAL;
+use tokio_tungstenite::tungstenite::protocol::{frame::coding::CloseCode, CloseFrame};
+use vertex_sdk::eip712_structs::{Order, StreamAuthentication};
+use crate::model::{
+    MarketLiquidityResponse, PlaceOrderResponse, ProductId, StreamResponseType, Subaccount, SubscriptionResult,
+};
+use crate::config::{Config, Network};
+use crate::signing::SigningDomain;
+use crate::request::next_request_id;
+use crate::stats::Stats;
+use crate::transport::{TungsteniteTransport, WsConnection, WsTransport};
 
-// Subscribe to a websocket stream
-pub async fn Subscribe(
-    sender: Sender<StreamResponseType>,
-    message: &str,
-    url: &str,
-) {
-    loop {
-        let connection = connect_async_with_config(
-            url,
-            Some(WebSocketConfig {
-                compression: Some(DeflateConfig::default()),
-                ..WebSocketConfig::default()
-            }),
-        )
-            .await;
+/// Exponential backoff parameters for reconnects and retries
+#[derive(Debug, Clone, Copy)]
+pub struct BackoffConfig {
+    pub base: Duration,        // first delay, and the delay after a reset
+    pub cap: Duration,         // delays never grow past this
+    pub multiplier: f64,       // growth factor applied after every failure
+    pub reset_after: Duration, // a connection that stays up this long resets the delay to `base`
+}
 
-        if let Err(e) = connection {
-            println!("Failed to connect: {}", e);
-            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-            continue;
+impl Default for BackoffConfig {
+    fn default() -> Self {
+        BackoffConfig {
+            base: Duration::from_millis(500),
+            cap: Duration::from_secs(30),
+            multiplier: 2.0,
+            reset_after: Duration::from_secs(5),
         }
+    }
+}
 
-        let (mut ws, _) = connection.unwrap();
+struct Backoff {
+    config: BackoffConfig,
+    current: Duration,
+}
 
-        if let Err(e) = ws.send(Message::Text(message.into())).await {
-            println!("Failed to send message: {}", e);
-            break;
-        }
+impl Backoff {
+    fn new(config: BackoffConfig) -> Self {
+        Backoff { config, current: config.base }
+    }
+
+    fn reset(&mut self) {
+        self.current = self.config.base;
+    }
+
+    // Jump straight to the longest delay
+    fn saturate(&mut self) {
+        self.current = self.config.cap;
+    }
+
+    // Sleep for the current delay with jitter in [delay/2, delay], then grow the delay
+    async fn wait(&mut self) {
+        let half = self.current / 2;
+        let jitter = thread_rng().gen_range(0..=half.as_millis() as u64);
+        tokio::time::sleep(half + Duration::from_millis(jitter)).await;
+        self.current = self.current.mul_f64(self.config.multiplier).min(self.config.cap);
+    }
+}
+
+// Token bucket: holds up to `capacity` tokens (the allowed burst), refilled at `per_second`
+struct RateLimiter {
+    capacity: f64,
+    per_second: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    fn new(per_second: f64) -> Self {
+        let capacity = per_second.max(1.0);
+        RateLimiter { capacity, per_second, tokens: capacity, refilled_at: Instant::now() }
+    }
 
-        let mut ping_interval = tokio::time::interval(std::time::Duration::from_secs(PING_FRAME_INTERVAL));
+    // Wait until a token is available, then take it.  A non-positive rate disables limiting.
+    async fn acquire(&mut self) {
+        if self.per_second <= 0.0 {
+            return;
+        }
         loop {
-            select! {
-                _ = ping_interval.tick() => {
-                    if let Err(e) = ws.send(Message::Ping(vec![])).await {
-                        println!("Failed to send ping: {}. Reconnecting...", e);
-                        break;
+            let now = Instant::now();
+            self.tokens = (self.tokens + now.duration_since(self.refilled_at).as_secs_f64() * self.per_second).min(self.capacity);
+            self.refilled_at = now;
+            if self.tokens >= 1.0 {
+                self.tokens -= 1.0;
+                return;
+            }
+            tokio::time::sleep(Duration::from_secs_f64((1.0 - self.tokens) / self.per_second)).await;
+        }
+    }
+}
+
+/// Credentials for authenticated streams (fills, positions, ...)
+#[derive(Debug, Clone)]
+pub struct StreamCredentials {
+    pub wallet: LocalWallet,
+    pub subaccount: [u8; 12], // see `encode_subaccount`
+}
+
+/// `Subscribe` as a `Stream`: runs the subscription in the background and yields its events.
+/// Dropping the stream stops the subscription.  `buffer` is the capacity of the internal channel.
+pub fn subscribe_stream(
+    subscriptions: Subscriptions,
+    config: Config,
+    credentials: Option<StreamCredentials>,
+    stats: Arc<Stats>,
+    buffer: usize,
+) -> impl Stream<Item = StreamResponseType> {
+    let (sender, receiver) = mpsc::channel(buffer);
+    tokio::spawn(async move {
+        if let Err(e) = Subscribe(sender, subscriptions, &config, credentials.as_ref(), stats).await {
+            warn!(error = %e, "subscription stopped");
+        }
+    });
+    ReceiverStream::new(receiver)
+}
+
+/// The set of active subscription frames, shared between `Subscribe` and its caller.
+/// Every frame in the set is replayed on each (re)connection; frames added while connected are sent right away,
+/// and frames removed while connected are unsubscribed on the same socket.
+#[derive(Debug, Clone, Default)]
+pub struct Subscriptions {
+    state: Arc<Mutex<SubscriptionState>>,
+    changed: Arc<Notify>,
+}
+
+#[derive(Debug, Default)]
+struct SubscriptionState {
+    frames: Vec<String>,
+    removed: Vec<String>, // removed frames not yet unsubscribed on the current connection
+    unrouted: HashSet<StreamKey>, // removed streams: events still in flight for them are dropped
+}
+
+// A stream as (type, product_id), the fields events carry to match them back to their subscription
+type StreamKey = (String, Option<ProductId>);
+
+fn stream_key(frame: &str) -> Option<StreamKey> {
+    let frame: serde_json::Value = serde_json::from_str(frame).ok()?;
+    let stream = frame.get("stream")?;
+    let stream_type = stream.get("type")?.as_str()?.to_string();
+    let product_id = stream.get("product_id").and_then(|id| id.as_u64()).map(|id| ProductId(id as u32));
+    Some((stream_type, product_id))
+}
+
+// Whether two subscribe frames are for the same stream.  Their ids may differ (see `next_request_id`),
+// so frames are compared by their `stream` object, or as plain text if they don't have one.
+fn same_stream(a: &str, b: &str) -> bool {
+    let stream = |frame: &str| {
+        serde_json::from_str::<serde_json::Value>(frame)
+            .ok()
+            .and_then(|mut frame| frame.get_mut("stream").map(serde_json::Value::take))
+    };
+    match (stream(a), stream(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+// The `id` of a request frame, which the gateway echoes in its reply
+fn frame_id(frame: &str) -> Option<u64> {
+    serde_json::from_str::<serde_json::Value>(frame).ok()?.get("id")?.as_u64()
+}
+
+// The unsubscribe counterpart of a subscribe frame: the same stream with `"method": "unsubscribe"` and a fresh id
+fn unsubscribe_frame(frame: &str) -> Option<String> {
+    let mut frame: serde_json::Value = serde_json::from_str(frame).ok()?;
+    let fields = frame.as_object_mut()?;
+    fields.insert("method".to_string(), json!("unsubscribe"));
+    fields.insert("id".to_string(), json!(next_request_id()));
+    Some(frame.to_string())
+}
+
+impl Subscriptions {
+    pub fn new(frames: Vec<String>) -> Self {
+        Subscriptions {
+            state: Arc::new(Mutex::new(SubscriptionState { frames, ..SubscriptionState::default() })),
+            changed: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Add a subscribe frame (e.g. a `SubscriptionRequest`) to the set.  Frames for a stream already in the set are ignored.
+    pub fn add(&self, frame: impl Into<String>) {
+        let frame = frame.into();
+        let mut state = self.state.lock().unwrap();
+        if !state.frames.iter().any(|active| same_stream(active, &frame)) {
+            if let Some(key) = stream_key(&frame) {
+                state.unrouted.remove(&key);
+            }
+            state.removed.retain(|removed| !same_stream(removed, &frame));
+            state.frames.push(frame);
+            self.changed.notify_one();
+        }
+    }
+
+    /// Unsubscribe: remove the frame for the same stream as `frame` (a subscribe frame, not an unsubscribe frame;
+    /// its id doesn't matter).  The stream is unsubscribed on the live socket without dropping the others,
+    /// and its events stop being routed, including any still in flight.  Returns false if it wasn't in the set.
+    pub fn remove(&self, frame: impl Into<String>) -> bool {
+        let frame = frame.into();
+        let mut state = self.state.lock().unwrap();
+        let position = match state.frames.iter().position(|active| same_stream(active, &frame)) {
+            Some(position) => position,
+            None => return false,
+        };
+        let removed = state.frames.remove(position);
+        if let Some(key) = stream_key(&removed) {
+            state.unrouted.insert(key);
+        }
+        state.removed.push(removed); // the frame as sent, so `send_pending` can find it
+        self.changed.notify_one();
+        true
+    }
+
+    /// Snapshot of the active frames
+    pub fn frames(&self) -> Vec<String> {
+        self.state.lock().unwrap().frames.clone()
+    }
+
+    // Whether `event` belongs to a stream that is still subscribed.  Events that can't be matched to a stream are kept.
+    fn routes(&self, event: &StreamResponseType) -> bool {
+        let state = self.state.lock().unwrap();
+        if state.unrouted.is_empty() {
+            return true;
+        }
+        match event.stream_type() {
+            Some(stream_type) => !state.unrouted.contains(&(stream_type.to_string(), event.product_id())),
+            None => true,
+        }
+    }
+
+    // Drop a frame the gateway rejected.  It was never subscribed, so there's nothing to unsubscribe.
+    fn reject(&self, frame: &str) {
+        self.state.lock().unwrap().frames.retain(|active| active != frame);
+    }
+
+    fn take_removed(&self) -> Vec<String> {
+        std::mem::take(&mut self.state.lock().unwrap().removed)
+    }
+}
+
+/// Why `Subscribe` stopped.  Transient failures (connects, dropped sockets, timeouts) are retried internally;
+/// these are the ones retrying can't fix.
+#[derive(Debug)]
+pub enum SubscribeError {
+    // `Config::max_consecutive_failures` connection attempts in a row failed (or dropped before `reset_after`)
+    TooManyFailures { failures: usize, last_error: String },
+    // the server closed the socket because the credentials were rejected or expired; reconnecting won't help
+    Unauthorized { reason: String },
+    // authentication failed for a reason other than the connection, e.g. a bad wallet or rejected signature
+    Authentication(AuthError),
+}
+
+impl fmt::Display for SubscribeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SubscribeError::TooManyFailures { failures, last_error } => {
+                write!(f, "Giving up after {} consecutive failures, last: {}", failures, last_error)
+            }
+            SubscribeError::Unauthorized { reason } => write!(f, "Connection closed as unauthorized: {}", reason),
+            SubscribeError::Authentication(e) => write!(f, "Authentication failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SubscribeError {}
+
+// Subscribe to one or more websocket streams over a single connection.
+// Events keep their product_id so the receiver can demultiplex them (see `StreamResponseType::product_id`).
+// Authenticated streams need `credentials`: every connection, including reconnects, is authenticated before
+// any subscribe frame is (re)sent.  All frames in `subscriptions` are replayed after each reconnect.
+// Reconnects forever unless `config.max_consecutive_failures` is set; returns Ok once the receiver is dropped.
+// Errors reconnecting can't fix (rejected credentials) are returned instead, see `SubscribeError`.  A subscribe frame
+// the gateway rejects (e.g. an unknown product) is dropped from `subscriptions` and its error forwarded as a
+// `SubscriptionResponse`; the other streams keep running.
+// Every successful reconnect is announced with `StreamResponseType::Reconnected`.
+// With `config.rotate_connection_after` set, connections are also replaced proactively, make-before-break, see `Config`.
+// How it reconnects after the server closes the socket depends on the close frame, see `ServerClose`.
+pub async fn Subscribe(
+    sender: Sender<StreamResponseType>,
+    subscriptions: Subscriptions,
+    config: &Config,
+    credentials: Option<&StreamCredentials>,
+    stats: Arc<Stats>,
+) -> Result<(), SubscribeError> {
+    let transport = TungsteniteTransport::from_config(config);
+    subscribe_with_transport(&transport, sender, subscriptions, config, credentials, stats).await
+}
+
+/// `Subscribe` to private streams (fills, position changes, ...; see `SubscriptionRequest::fill`) as `wallet`'s
+/// `subaccount` (see `encode_subaccount`).  Every connection, reconnects included, is authenticated and the
+/// acknowledgement awaited before any subscribe frame is sent on it.
+pub async fn subscribe_authenticated(
+    wallet: LocalWallet,
+    subaccount: [u8; 12],
+    subscriptions: Subscriptions,
+    sender: Sender<StreamResponseType>,
+    config: &Config,
+    stats: Arc<Stats>,
+) -> Result<(), SubscribeError> {
+    let credentials = StreamCredentials { wallet, subaccount };
+    Subscribe(sender, subscriptions, config, Some(&credentials), stats).await
+}
+
+/// `Subscribe` over a caller-provided transport, e.g. a `MockTransport` feeding scripted frames
+#[tracing::instrument(name = "subscribe", skip_all, fields(url = %config.subscription_url))]
+pub async fn subscribe_with_transport<T: WsTransport>(
+    transport: &T,
+    sender: Sender<StreamResponseType>,
+    subscriptions: Subscriptions,
+    config: &Config,
+    credentials: Option<&StreamCredentials>,
+    stats: Arc<Stats>,
+) -> Result<(), SubscribeError> {
+    let result = subscribe_loop(transport, sender, subscriptions, config, credentials, &stats).await;
+    stats.record_disconnected(); // however the loop ended, it ended mid-connection
+    result
+}
+
+async fn subscribe_loop<T: WsTransport>(
+    transport: &T,
+    sender: Sender<StreamResponseType>,
+    subscriptions: Subscriptions,
+    config: &Config,
+    credentials: Option<&StreamCredentials>,
+    stats: &Stats,
+) -> Result<(), SubscribeError> {
+    let backoff_config = config.backoff;
+    let read_timeout = 3 * config.ping_frame_interval; // seconds without any inbound frame before the connection is considered dead
+    let mut backoff = Backoff::new(backoff_config);
+    let mut failures = 0;
+    let mut subscribed_before = false;
+    let mut connected_before = false;
+    let rotate_after = config.rotate_connection_after.map(Duration::from_secs);
+    loop {
+        let mut connected_at = None;
+        let mut close = ServerClose::Other;
+        let error = 'connection: {
+            let mut ws = match transport.connect(config.subscription_url.as_str()).await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    warn!(error = %e, "failed to connect");
+                    break 'connection format!("failed to connect: {}", e);
+                }
+            };
+            connected_at = Some(Instant::now());
+            stats.record_connected(connected_before);
+            connected_before = true;
+            info!("connected");
+
+            if let Some(credentials) = credentials {
+                let valid_for = Duration::from_secs(config.auth_expiry);
+                if let Err(e) = authenticate(&mut ws, &credentials.wallet, credentials.subaccount, config.network, valid_for).await {
+                    if !e.is_transient() {
+                        return Err(SubscribeError::Authentication(e));
                     }
+                    warn!(error = %e, "failed to authenticate, reconnecting");
+                    break 'connection format!("failed to authenticate: {}", e);
                 }
-                message = ws.next() => {
-                    match message {
-                        Some(Ok(msg)) => {
-                            if msg.is_text() {
-                                match msg.into_text() {
-                                    Ok(text) => {
-                                        match serde_json::from_str::<StreamResponseType>(&text) {
-                                            Ok(resp) => {
-                                                if sender.send(resp).await.is_err() {
-                                                    println!("Receiver dropped");
-                                                    break;
-                                                }
-                                            }
-                                            Err(e) => {
-                                                println!("Unable to parse message: {}", e);
-                                            }
+            }
+
+            // frames sent on this connection; anything else in `subscriptions` still needs sending
+            let mut sent: Vec<String> = Vec::new();
+            if let Err(e) = send_pending(&mut ws, &subscriptions, &mut sent).await {
+                warn!(error = %e, "failed to send subscriptions, reconnecting");
+                break 'connection format!("failed to send subscriptions: {}", e);
+            }
+            if !sent.is_empty() {
+                info!(subscriptions = sent.len(), "subscribed");
+            }
+            if subscribed_before && sender.send(StreamResponseType::Reconnected).await.is_err() {
+                info!("receiver dropped, unsubscribing");
+                return Ok(());
+            }
+            subscribed_before = true;
+
+            let mut ping_interval = tokio::time::interval(std::time::Duration::from_secs(config.ping_frame_interval));
+            let mut last_message_at = Instant::now(); // any inbound frame (including pongs) counts as traffic
+            let mut rotate_at = rotate_after.map(|after| tokio::time::Instant::now() + after);
+            let mut replacement: Option<Replacement<T::Connection>> = None; // subscribed, not yet live
+            let mut last_delivered: HashMap<StreamKey, Delivered> = HashMap::new(); // last event sent on, per stream
+            let mut backlog: VecDeque<String> = VecDeque::new(); // frames held by the replacement, handled before reading more
+            loop {
+                // switch once the replacement has caught up with this connection, or stop waiting and resnapshot
+                let switch = match &replacement {
+                    Some(next) if !next.held.is_empty() && next.overlaps(&last_delivered) => Some(true),
+                    Some(next) if tokio::time::Instant::now() >= next.deadline => Some(false),
+                    _ => None,
+                };
+                if let Some(overlapped) = switch {
+                    let next = replacement.take().expect("a replacement to switch to");
+                    let mut retired = std::mem::replace(&mut ws, next.connection);
+                    sent = next.sent;
+                    if let Err(e) = retired.close().await {
+                        debug!(error = %e, "failed to close the replaced connection");
+                    }
+                    stats.record_connected(false);
+                    rotate_at = rotate_after.map(|after| tokio::time::Instant::now() + after);
+                    if overlapped {
+                        info!("switched to the replacement connection");
+                        backlog.extend(next.held.into_iter().filter(|text| !already_delivered(text, &last_delivered)));
+                    } else {
+                        warn!("replacement connection never caught up with the replaced one, switching and resnapshotting");
+                        if sender.send(StreamResponseType::Reconnected).await.is_err() {
+                            info!("receiver dropped, unsubscribing");
+                            return Ok(());
+                        }
+                        backlog.extend(next.held);
+                    }
+                    // catch up on subscription changes made since the replacement subscribed
+                    if let Err(e) = send_pending(&mut ws, &subscriptions, &mut sent).await {
+                        warn!(error = %e, "failed to send subscription, reconnecting");
+                        break 'connection format!("failed to send subscription: {}", e);
+                    }
+                }
+
+                let overlap_deadline = replacement.as_ref().map(|next| next.deadline);
+                let message = match backlog.pop_front() {
+                    Some(text) => Some(Ok(Message::Text(text))),
+                    None => select! {
+                        _ = subscriptions.changed.notified() => {
+                            if let Err(e) = send_pending(&mut ws, &subscriptions, &mut sent).await {
+                                warn!(error = %e, "failed to send subscription, reconnecting");
+                                break 'connection format!("failed to send subscription: {}", e);
+                            }
+                            continue;
+                        }
+                        _ = ping_interval.tick() => {
+                            if last_message_at.elapsed() >= Duration::from_secs(read_timeout) {
+                                warn!(read_timeout_secs = read_timeout, "no messages received, reconnecting");
+                                break 'connection format!("no messages received in {}s", read_timeout);
+                            }
+                            if let Err(e) = ws.send(Message::Ping(vec![])).await {
+                                warn!(error = %e, "failed to send ping, reconnecting");
+                                break 'connection format!("failed to send ping: {}", e);
+                            }
+                            continue;
+                        }
+                        _ = wait_until(rotate_at) => {
+                            // make before break: subscribe a replacement while this connection keeps delivering
+                            rotate_at = None;
+                            match open_replacement(transport, config, credentials, &subscriptions).await {
+                                Ok((connection, replacement_sent)) => {
+                                    info!("replacement connection subscribed, switching once it catches up");
+                                    let overlap_timeout = Duration::from_secs(read_timeout);
+                                    replacement = Some(Replacement::new(connection, replacement_sent, overlap_timeout));
+                                }
+                                Err(e) => {
+                                    warn!(error = %e, "failed to open a replacement connection, keeping this one");
+                                    rotate_at = rotate_after.map(|after| tokio::time::Instant::now() + after);
+                                }
+                            }
+                            continue;
+                        }
+                        _ = wait_until(overlap_deadline) => continue, // switched above
+                        message = next_replacement_frame(&mut replacement) => match message {
+                            // held back until the replacement has caught up, see the switch above
+                            Some(Ok(Message::Text(text))) => {
+                                if let Some(next) = replacement.as_mut() {
+                                    next.hold(text);
+                                }
+                                continue;
+                            }
+                            Some(Ok(Message::Ping(payload))) => {
+                                if let Some(next) = replacement.as_mut() {
+                                    let _ = next.connection.send(Message::Pong(payload)).await;
+                                }
+                                continue;
+                            }
+                            Some(Ok(_)) => continue,
+                            Some(Err(_)) | None => {
+                                warn!("replacement connection failed before going live, keeping this one");
+                                replacement = None;
+                                rotate_at = rotate_after.map(|after| tokio::time::Instant::now() + after);
+                                continue;
+                            }
+                        },
+                        message = ws.next() => message,
+                    },
+                };
+
+                match message {
+                    Some(Ok(msg)) => {
+                        last_message_at = Instant::now();
+                        stats.record_message();
+                        match msg {
+                            Message::Text(text) => {
+                                match StreamResponseType::from_json(&text) {
+                                    Ok(StreamResponseType::SubscriptionResponse(SubscriptionResult::Error(e))) => {
+                                        warn!(error = %e.error, id = ?e.id, error_code = ?e.error_code, "request rejected");
+                                        // a rejected subscribe frame would be rejected again after every reconnect: drop just that
+                                        // stream and forward the error, the others carry on
+                                        if let Some(position) = sent.iter().position(|frame| e.id.is_some() && frame_id(frame) == e.id) {
+                                            let frame = sent.remove(position);
+                                            warn!(frame = %frame, "subscription rejected, dropping it");
+                                            subscriptions.reject(&frame);
+                                        }
+                                        let resp = StreamResponseType::SubscriptionResponse(SubscriptionResult::Error(e));
+                                        if sender.send(resp).await.is_err() {
+                                            info!("receiver dropped, unsubscribing");
+                                            return Ok(());
+                                        }
+                                    }
+                                    Ok(resp) => {
+                                        if !subscriptions.routes(&resp) {
+                                            debug!(stream = ?resp.stream_type(), "dropping event for an unsubscribed stream");
+                                            continue;
+                                        }
+                                        let position = stream_position(&resp);
+                                        if sender.send(resp).await.is_err() {
+                                            info!("receiver dropped, unsubscribing");
+                                            return Ok(());
+                                        }
+                                        if let Some((key, _, timestamp)) = position {
+                                            record_delivered(&mut last_delivered, key, timestamp, text);
                                         }
                                     }
                                     Err(e) => {
-                                        println!("Failed to convert message to text: {}", e);
+                                        stats.record_parse_error();
+                                        debug!(error = %e, message = %text, "unable to parse message");
                                     }
                                 }
                             }
+                            Message::Ping(payload) => {
+                                if let Err(e) = ws.send(Message::Pong(payload)).await {
+                                    warn!(error = %e, "failed to send pong, reconnecting");
+                                    break 'connection format!("failed to send pong: {}", e);
+                                }
+                            }
+                            Message::Pong(_) => {} // only needed to refresh last_message_at
+                            Message::Close(frame) => {
+                                close = ServerClose::from_frame(frame.as_ref());
+                                match close {
+                                    ServerClose::Unauthorized => {
+                                        warn!(frame = ?frame, "websocket closed by server as unauthorized");
+                                        let reason = frame.map(|frame| frame.reason.into_owned()).unwrap_or_default();
+                                        return Err(SubscribeError::Unauthorized { reason });
+                                    }
+                                    ServerClose::RateLimited => {
+                                        warn!(frame = ?frame, "websocket closed by server as rate limited, backing off");
+                                    }
+                                    ServerClose::Normal | ServerClose::Other => {
+                                        info!(frame = ?frame, "websocket closed by server, reconnecting");
+                                    }
+                                }
+                                break 'connection format!("websocket closed by server: {:?}", frame);
+                            }
+                            Message::Binary(data) => {
+                                debug!(bytes = data.len(), "unexpected binary message");
+                            }
+                            Message::Frame(_) => {} // raw frames are never returned when reading
                         }
-                        Some(Err(e)) => {
-                            println!("WebSocket error: {}. Reconnecting...", e);
-                            break;
-                        }
-                        None => {
-                            println!("WebSocket closed. Reconnecting...");
-                            break;
-                        }
+                    }
+                    Some(Err(e)) => {
+                        warn!(error = %e, "websocket error, reconnecting");
+                        break 'connection format!("websocket error: {}", e);
+                    }
+                    None => {
+                        info!("websocket closed, reconnecting");
+                        break 'connection "websocket closed".to_string();
                     }
                 }
             }
+        };
+        stats.record_disconnected();
+
+        // a connection that stayed up long enough counts as a success
+        match connected_at {
+            Some(connected_at) if connected_at.elapsed() >= backoff_config.reset_after => {
+                backoff.reset();
+                failures = 0;
+            }
+            _ => failures += 1,
+        }
+        if let Some(max_failures) = config.max_consecutive_failures {
+            if failures >= max_failures {
+                return Err(SubscribeError::TooManyFailures { failures, last_error: error });
+            }
+        }
+        match close {
+            ServerClose::Normal => backoff.reset(),
+            ServerClose::RateLimited => backoff.saturate(), // reconnecting quickly would only prolong the limit
+            ServerClose::Unauthorized | ServerClose::Other => {}
         }
+        backoff.wait().await;
     }
 }
 
+// Resolves at `at`, or never if it's None
+pub(crate) async fn wait_until(at: Option<tokio::time::Instant>) {
+    match at {
+        Some(at) => tokio::time::sleep_until(at).await,
+        None => std::future::pending().await,
+    }
+}
 
-// TODO improvement - keep the client live so the connection doesn't have to be reestablished every query
-pub async fn QueryMarketLiquidity(
-    message: &str,
-    url: &str,
-) -> MarketLiquidityResponse {
-    loop {
-        let connection = connect_async_with_config(
-            url,
-            Some(WebSocketConfig {
-                compression: Some(DeflateConfig::default()),
-                ..WebSocketConfig::default()
-            }),
-        )
-            .await;
+// The next frame from a pending replacement connection, or never if there isn't one
+async fn next_replacement_frame<C: WsConnection>(
+    replacement: &mut Option<Replacement<C>>,
+) -> Option<Result<Message, tungstenite::Error>> {
+    match replacement {
+        Some(next) => next.connection.next().await,
+        None => std::future::pending().await,
+    }
+}
+
+// A connection subscribed to take over from the current one, see `Config::rotate_connection_after`.  Its text frames
+// are held back until it has caught up with the current one, so nothing is lost or delivered twice in the switch.
+struct Replacement<C> {
+    connection: C,
+    sent: Vec<String>, // frames sent on it
+    held: Vec<String>, // text frames received on it, in order
+    first_held: HashMap<StreamKey, (u128, String)>, // per stream, where the first event held picks up and its frame
+    deadline: tokio::time::Instant, // switch anyway (and resnapshot) if it hasn't caught up by then
+}
+
+impl<C> Replacement<C> {
+    fn new(connection: C, sent: Vec<String>, overlap_timeout: Duration) -> Self {
+        Replacement {
+            connection,
+            sent,
+            held: Vec::new(),
+            first_held: HashMap::new(),
+            deadline: tokio::time::Instant::now() + overlap_timeout,
+        }
+    }
+
+    fn hold(&mut self, text: String) {
+        if let Some((key, from, _)) = StreamResponseType::from_json(&text).ok().as_ref().and_then(stream_position) {
+            self.first_held.entry(key).or_insert_with(|| (from, text.clone()));
+        }
+        self.held.push(text);
+    }
 
-        let (mut ws, _) = match connection {
-            Ok(conn) => conn,
-            Err(e) => {
-                println!("Failed to connect: {}", e);
-                continue;
+    // True once, for every stream the current connection has delivered on, the replacement's events pick up no later
+    // than the last event delivered: between them the two connections then miss nothing
+    fn overlaps(&self, last_delivered: &HashMap<StreamKey, Delivered>) -> bool {
+        last_delivered.iter().all(|(key, delivered)| match self.first_held.get(key) {
+            // a book_depth event continues exactly from the previous one's max_timestamp
+            Some((first, _)) if key.0 == "book_depth" => *first <= delivered.timestamp,
+            // other streams can put several events on one timestamp: starting on that one, the replacement must
+            // start with a frame already delivered, or it may have missed others delivered only by this connection
+            Some((first, frame)) => {
+                *first < delivered.timestamp || (*first == delivered.timestamp && delivered.frames.contains(frame))
             }
+            None => false,
+        })
+    }
+}
+
+// The last event the current connection delivered on a stream
+struct Delivered {
+    timestamp: u128,
+    frames: Vec<String>, // every frame delivered at `timestamp`, since trades, fills etc. can share one
+}
+
+// The stream an event belongs to, the timestamp it picks up from and its own.  A book_depth event covers
+// (last_max_timestamp, max_timestamp]; any other event just its timestamp.  None for acks, synthetic events and
+// timestamps that don't parse, which aren't de-duplicated.
+fn stream_position(event: &StreamResponseType) -> Option<(StreamKey, u128, u128)> {
+    let timestamp = match event {
+        StreamResponseType::BookDepth(data) => {
+            let key = ("book_depth".to_string(), Some(data.product_id));
+            return Some((key, data.last_max_timestamp, data.max_timestamp));
+        }
+        StreamResponseType::Trade(data) => &data.timestamp,
+        StreamResponseType::BestBidOffer(data) => &data.timestamp,
+        StreamResponseType::Fill(data) => &data.timestamp,
+        StreamResponseType::PositionChange(data) => &data.timestamp,
+        StreamResponseType::MarketPrice(data) => &data.update_time,
+        StreamResponseType::Candlestick(data) => &data.timestamp,
+        StreamResponseType::Liquidation(data) => &data.timestamp,
+        StreamResponseType::SubscriptionResponse(_) | StreamResponseType::Reconnected | StreamResponseType::Unknown => {
+            return None;
+        }
+    };
+    let timestamp = timestamp.parse().ok()?;
+    Some(((event.stream_type()?.to_string(), event.product_id()), timestamp, timestamp))
+}
+
+fn record_delivered(last_delivered: &mut HashMap<StreamKey, Delivered>, key: StreamKey, timestamp: u128, text: String) {
+    let delivered = last_delivered.entry(key).or_insert(Delivered { timestamp, frames: Vec::new() });
+    if delivered.timestamp != timestamp {
+        delivered.timestamp = timestamp;
+        delivered.frames.clear();
+    }
+    delivered.frames.push(text);
+}
+
+// A frame the replaced connection already delivered: one before the last it sent on the stream, or the same frame
+// at that timestamp.  A book_depth's max_timestamp identifies it, so any book_depth at that timestamp was delivered.
+fn already_delivered(text: &str, last_delivered: &HashMap<StreamKey, Delivered>) -> bool {
+    match StreamResponseType::from_json(text).ok().as_ref().and_then(stream_position) {
+        Some((key, _, timestamp)) => match last_delivered.get(&key) {
+            Some(delivered) if timestamp == delivered.timestamp => {
+                key.0 == "book_depth" || delivered.frames.iter().any(|frame| frame == text)
+            }
+            Some(delivered) => timestamp < delivered.timestamp,
+            None => false,
+        },
+        None => false,
+    }
+}
+
+// Connect, authenticate and subscribe the connection that takes over from the current one, see
+// `Config::rotate_connection_after`.  Returns it along with the frames sent on it.
+async fn open_replacement<T: WsTransport>(
+    transport: &T,
+    config: &Config,
+    credentials: Option<&StreamCredentials>,
+    subscriptions: &Subscriptions,
+) -> Result<(T::Connection, Vec<String>), String> {
+    let mut ws = transport
+        .connect(config.subscription_url.as_str())
+        .await
+        .map_err(|e| format!("failed to connect: {}", e))?;
+    if let Some(credentials) = credentials {
+        let valid_for = Duration::from_secs(config.auth_expiry);
+        authenticate(&mut ws, &credentials.wallet, credentials.subaccount, config.network, valid_for)
+            .await
+            .map_err(|e| format!("failed to authenticate: {}", e))?;
+    }
+    let mut sent = Vec::new();
+    for frame in subscriptions.frames() {
+        ws.send(Message::Text(frame.clone())).await.map_err(|e| format!("failed to send subscription: {}", e))?;
+        sent.push(frame);
+    }
+    Ok((ws, sent))
+}
+
+// How `subscribe_with_transport` reacts to the server closing the socket
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ServerClose {
+    Normal,       // a clean close, going away or restarting: reconnect after the base delay
+    RateLimited,  // too many connections or requests: wait the longest backoff delay before reconnecting
+    Unauthorized, // credentials rejected or expired: stop with `SubscribeError::Unauthorized`
+    Other,        // anything else, including a close without a frame: the usual backoff
+}
+
+impl ServerClose {
+    fn from_frame(frame: Option<&CloseFrame>) -> Self {
+        let frame = match frame {
+            Some(frame) => frame,
+            None => return ServerClose::Other,
         };
+        // the reason text is more specific than the code, which servers tend to leave at Policy or Normal
+        let reason = frame.reason.to_lowercase();
+        if reason.contains("rate limit") || reason.contains("too many") {
+            return ServerClose::RateLimited;
+        }
+        if reason.contains("unauthori") || (reason.contains("auth") && (reason.contains("fail") || reason.contains("expired"))) {
+            return ServerClose::Unauthorized;
+        }
+        match frame.code {
+            CloseCode::Normal | CloseCode::Away | CloseCode::Restart => ServerClose::Normal,
+            CloseCode::Again => ServerClose::RateLimited,      // 1013, try again later
+            CloseCode::Iana(3000) => ServerClose::Unauthorized, // registered as "Unauthorized"
+            _ => ServerClose::Other,
+        }
+    }
+}
+
 
-        if let Err(e) = ws.send(Message::Text(message.into())).await {
-            println!("Failed to send message: {}.  Retrying...", e);
-            continue;
+// Unsubscribe frames removed from `subscriptions` that were sent on this connection,
+// then send the frames in `subscriptions` that haven't been sent on this connection yet
+async fn send_pending<S: WsConnection>(ws: &mut S, subscriptions: &Subscriptions, sent: &mut Vec<String>) -> Result<(), tungstenite::Error> {
+    for frame in subscriptions.take_removed() {
+        if let Some(position) = sent.iter().position(|active| *active == frame) {
+            if let Some(unsubscribe) = unsubscribe_frame(&frame) {
+                ws.send(Message::Text(unsubscribe)).await?;
+            }
+            sent.remove(position);
         }
+    }
+    for frame in subscriptions.frames() {
+        if !sent.contains(&frame) {
+            ws.send(Message::Text(frame.clone())).await?;
+            sent.push(frame);
+        }
+    }
+    Ok(())
+}
 
-        match ws.next().await {
-            Some(Ok(msg)) => {
-                if msg.is_text() {
-                    match msg.into_text() {
-                        Ok(text) => {
-                            match serde_json::from_str::<MarketLiquidityResponse>(&text) {
-                                Ok(resp) => return resp,
-                                Err(e) => {
-                                    println!("Failed to parse response: {}.  Retrying...", e);
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            println!("Failed to convert message to text: {}.  Retrying...", e);
-                        }
+#[derive(Debug)]
+pub enum QueryError {
+    // transient: retried with backoff on a fresh connection
+    Connect(tungstenite::Error),
+    Send(tungstenite::Error),
+    Receive(tungstenite::Error),
+    Closed,
+    Timeout(Duration), // no reply within the query timeout
+    // terminal: returned immediately
+    InvalidDepth(usize),
+    Gateway { error: String, error_code: Option<i64> }, // well-formed error response from the gateway
+    Parse(serde_json::Error),
+    NonText(Message),
+}
+
+impl QueryError {
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            QueryError::Connect(_) | QueryError::Send(_) | QueryError::Receive(_) | QueryError::Closed | QueryError::Timeout(_)
+        )
+    }
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryError::Connect(e) => write!(f, "Failed to connect: {}", e),
+            QueryError::Send(e) => write!(f, "Failed to send message: {}", e),
+            QueryError::Receive(e) => write!(f, "Error receiving message: {}", e),
+            QueryError::Closed => write!(f, "Connection closed by the server"),
+            QueryError::Timeout(timeout) => write!(f, "No response within {:?}", timeout),
+            QueryError::InvalidDepth(depth) => {
+                write!(f, "Invalid market liquidity depth {}: must be 1..={}", depth, MAX_MARKET_LIQ_QUERY_DEPTH)
+            }
+            QueryError::Gateway { error, error_code } => {
+                write!(f, "Gateway error: {} (code {:?})", error, error_code)
+            }
+            QueryError::Parse(e) => write!(f, "Failed to parse response: {}", e),
+            QueryError::NonText(msg) => write!(f, "Non-text message received: {:?}", msg),
+        }
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+pub const MAX_MARKET_LIQ_QUERY_DEPTH: usize = 100; // the gateway clamps or rejects anything deeper
+
+/// Reject depths the gateway won't honor before anything is sent
+pub fn validate_depth(depth: usize) -> Result<(), QueryError> {
+    if depth == 0 || depth > MAX_MARKET_LIQ_QUERY_DEPTH {
+        return Err(QueryError::InvalidDepth(depth));
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct GatewayErrorResponse {
+    error: String,
+    error_code: Option<i64>,
+}
+
+// Parse a query reply, recognizing the gateway's error shape when it isn't a `T`
+fn parse_query_response<T: DeserializeOwned>(text: &str) -> Result<T, QueryError> {
+    serde_json::from_str::<T>(text).map_err(|e| match serde_json::from_str::<GatewayErrorResponse>(text) {
+        Ok(resp) => QueryError::Gateway { error: resp.error, error_code: resp.error_code },
+        Err(_) => QueryError::Parse(e),
+    })
+}
+
+// Tag a query with a fresh `id` (see `next_request_id`) for its reply to echo.
+// Messages that aren't JSON objects are sent as they are, untagged.
+fn with_request_id(message: &str) -> (Option<u64>, String) {
+    match serde_json::from_str::<serde_json::Value>(message) {
+        Ok(serde_json::Value::Object(mut fields)) => {
+            let id = next_request_id();
+            fields.insert("id".to_string(), json!(id));
+            (Some(id), serde_json::Value::Object(fields).to_string())
+        }
+        _ => (None, message.to_string()),
+    }
+}
+
+fn reply_id(text: &str) -> Option<u64> {
+    #[derive(Deserialize)]
+    struct Reply {
+        id: Option<u64>,
+    }
+    serde_json::from_str::<Reply>(text).ok()?.id
+}
+
+pub type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Gateway client that keeps its websocket alive across queries.
+/// Connects lazily on the first query and reconnects (with backoff) whenever the socket dies.
+pub struct GatewayClient<T: WsTransport = TungsteniteTransport> {
+    transport: T,
+    url: String,
+    ws: Option<T::Connection>,
+    backoff: Backoff,
+    max_retries: usize, // transient failures retried per query before giving up
+    timeout: Duration, // how long to wait for a reply before treating the socket as dead
+    rate_limiter: RateLimiter, // shared by every query (and retry) sent through this client
+    order_domains: HashMap<(Network, ProductId), SigningDomain>, // built on first use, see `order_domain`
+}
+
+impl GatewayClient {
+    pub fn new(
+        url: &str,
+        backoff_config: BackoffConfig,
+        max_retries: usize,
+        timeout: Duration,
+        compression: Option<DeflateConfig>,
+        rate_limit: f64, // queries per second
+        proxy_url: Option<String>,
+    ) -> Self {
+        GatewayClient::with_transport(
+            TungsteniteTransport { compression, proxy_url, ..TungsteniteTransport::default() },
+            url,
+            backoff_config,
+            max_retries,
+            timeout,
+            rate_limit,
+        )
+    }
+
+    /// Client for `config.gateway_url` with the query settings from `config`
+    pub fn from_config(config: &Config) -> Self {
+        GatewayClient::with_transport(
+            TungsteniteTransport::from_config(config),
+            &config.gateway_url,
+            config.backoff,
+            config.query_max_retries,
+            Duration::from_secs(config.query_timeout),
+            config.query_rate_limit,
+        )
+    }
+}
+
+impl<T: WsTransport> GatewayClient<T> {
+    /// Client that connects through `transport`, e.g. a `MockTransport` in tests
+    pub fn with_transport(
+        transport: T,
+        url: &str,
+        backoff_config: BackoffConfig,
+        max_retries: usize,
+        timeout: Duration,
+        rate_limit: f64, // queries per second
+    ) -> Self {
+        GatewayClient {
+            transport,
+            url: url.to_string(),
+            ws: None,
+            backoff: Backoff::new(backoff_config),
+            max_retries,
+            timeout,
+            rate_limiter: RateLimiter::new(rate_limit),
+            order_domains: HashMap::new(),
+        }
+    }
+
+    pub async fn query_market_liquidity(
+        &mut self,
+        product_id: ProductId,
+        depth: usize,
+    ) -> Result<MarketLiquidityResponse, QueryError> {
+        validate_depth(depth)?;
+        let message = json!({
+          "type": "market_liquidity",
+          "product_id": product_id,
+          "depth": depth
+        })
+        .to_string();
+
+        self.query(&message).await
+    }
+
+    /// Sign `order` as `wallet`'s `subaccount` (see `encode_subaccount`) and place it.
+    /// Returns the gateway's ack; rejections come back as `QueryError::Gateway`.
+    /// Retries resend the same signed order, which the gateway will not execute twice.
+    pub async fn place_order(
+        &mut self,
+        wallet: &LocalWallet,
+        subaccount: [u8; 12],
+        network: Network,
+        order: &OrderParams,
+    ) -> Result<PlaceOrderResponse, OrderError> {
+        let sender = build_sender(wallet.address(), subaccount);
+        let domain = self.order_domain(network, order.product_id);
+        let signature = generate_order_signature(&sender, order, wallet, domain)?;
+        let message = json!({
+          "place_order": {
+            "product_id": order.product_id,
+            "order": {
+              "sender": sender,
+              "priceX18": order.price_x18.to_string(),
+              "amount": order.amount.to_string(),
+              "expiration": order.expiration.to_string(),
+              "nonce": order.nonce.to_string()
+            },
+            "signature": signature
+          }
+        })
+        .to_string();
+
+        self.query(&message).await.map_err(OrderError::Query)
+    }
+
+    /// Domain `product_id`'s orders are signed under on `network`, cached so repeated orders skip rebuilding it
+    pub fn order_domain(&mut self, network: Network, product_id: ProductId) -> &SigningDomain {
+        self.order_domains
+            .entry((network, product_id))
+            .or_insert_with(|| SigningDomain::new(network.order_domain(product_id)))
+    }
+
+    /// Send a query and parse the reply.  Each attempt is tagged with a unique `id` and only the reply echoing
+    /// it is accepted.  Transient failures (connect/send/receive, dropped socket) reconnect and retry with backoff
+    /// up to `max_retries` times; anything else is returned immediately.
+    pub async fn query<R: DeserializeOwned>(&mut self, message: &str) -> Result<R, QueryError> {
+        let mut retries = 0;
+        loop {
+            match self.query_once(message).await {
+                Ok(resp) => {
+                    self.backoff.reset();
+                    return Ok(resp);
+                }
+                Err(e) if e.is_transient() => {
+                    self.ws = None; // the socket is unusable, reconnect on the next attempt
+                    if retries >= self.max_retries {
+                        return Err(e);
                     }
-                } else {
-                    println!("Non-text message received");
+                    warn!(error = %e, retry = retries + 1, max_retries = self.max_retries, "gateway query failed, retrying");
+                    retries += 1;
+                    self.backoff.wait().await;
                 }
+                Err(e) => return Err(e),
             }
-            Some(Err(e)) => {
-                println!("Error receiving message: {}.  Retrying...", e);
-                tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-            }
+        }
+    }
+
+    async fn query_once<R: DeserializeOwned>(&mut self, message: &str) -> Result<R, QueryError> {
+        let ws = match self.ws.as_mut() {
+            Some(ws) => ws,
             None => {
-                println!("Connection closed by the server.  Retrying...");
-                tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+                let ws = self.transport.connect(self.url.as_str()).await.map_err(QueryError::Connect)?;
+                self.ws.insert(ws)
             }
-        }
+        };
+
+        let (id, message) = with_request_id(message);
+        self.rate_limiter.acquire().await;
+        ws.send(Message::Text(message)).await.map_err(QueryError::Send)?;
+
+        // the reply is the text frame echoing our id; control frames and replies to earlier (abandoned)
+        // requests can arrive in between on a long-lived socket
+        let timeout = self.timeout;
+        let reply = tokio::time::timeout(timeout, async {
+            loop {
+                match ws.next().await {
+                    Some(Ok(Message::Text(text))) => match (id, reply_id(&text)) {
+                        (Some(id), Some(reply_id)) if reply_id != id => {
+                            debug!(id, reply_id, "skipping reply to another request");
+                        }
+                        _ => return parse_query_response(&text), // ours, or a reply that doesn't echo ids
+                    },
+                    Some(Ok(Message::Ping(payload))) => {
+                        ws.send(Message::Pong(payload)).await.map_err(QueryError::Send)?;
+                    }
+                    Some(Ok(Message::Pong(_))) => continue,
+                    Some(Ok(Message::Close(_))) | None => return Err(QueryError::Closed),
+                    Some(Ok(msg)) => return Err(QueryError::NonText(msg)),
+                    Some(Err(e)) => return Err(QueryError::Receive(e)),
+                }
+            }
+        })
+            .await;
 
-        println!("Retrying...");
+        reply.unwrap_or(Err(QueryError::Timeout(timeout)))
     }
 }
 
+/// Thin wrapper kept for backward compatibility: opens a fresh connection per call.
+/// Prefer holding a `GatewayClient` so the connection is reused.
+pub async fn QueryMarketLiquidity(
+    product_id: ProductId,
+    depth: usize,
+    config: &Config,
+) -> Result<MarketLiquidityResponse, QueryError> {
+    GatewayClient::from_config(config)
+        .query_market_liquidity(product_id, depth)
+        .await
+}
+
 
 
-fn _get_expiration() -> u128 {
+// Unix ms, the base every expiration and nonce is counted from
+fn unix_millis() -> u64 {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .expect("Time went backwards")
-        .as_millis()
-        + (1 * 1000) // 1s
+        .as_millis() as u64
+}
+
+/// Expiration (unix ms) for an `authenticate` request signed now: the gateway rejects it once `valid_for` has passed,
+/// so too short a window fails on slow links.  See `Config::auth_expiry`.
+pub fn auth_expiration(valid_for: Duration) -> u64 {
+    unix_millis() + valid_for.as_millis() as u64
+}
+
+/// How long an order rests, encoded in the top two bits of its expiration
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TimeInForce {
+    #[default]
+    GoodTillTime, // rests until the expiration
+    ImmediateOrCancel,
+    FillOrKill,
+    PostOnly, // rejected if it would take liquidity
+}
+
+impl TimeInForce {
+    fn bits(self) -> u64 {
+        match self {
+            TimeInForce::GoodTillTime => 0,
+            TimeInForce::ImmediateOrCancel => 1,
+            TimeInForce::FillOrKill => 2,
+            TimeInForce::PostOnly => 3,
+        }
+    }
+}
+
+const TIME_IN_FORCE_SHIFT: u32 = 62;
+const REDUCE_ONLY_BIT: u64 = 1 << 61;
+
+/// `OrderParams::expiration` for an order that expires `valid_for` from now: unix seconds in the low bits,
+/// `time_in_force` in the top two bits and bit 61 set for reduce-only orders (which can only shrink a position)
+pub fn order_expiration(valid_for: Duration, time_in_force: TimeInForce, reduce_only: bool) -> u64 {
+    let expires_at = (unix_millis() + valid_for.as_millis() as u64) / 1000;
+    let reduce_only = if reduce_only { REDUCE_ONLY_BIT } else { 0 };
+    expires_at | reduce_only | (time_in_force.bits() << TIME_IN_FORCE_SHIFT)
+}
+
+const ORDER_RECV_WINDOW_MS: u64 = 90_000; // orders not processed within this long are discarded
+
+/// Order nonce: the top 44 bits are the time (unix ms) after which the gateway discards the order,
+/// the low 20 bits are random so orders sent in the same millisecond differ
+pub fn order_nonce() -> u64 {
+    let recv_time = unix_millis() + ORDER_RECV_WINDOW_MS;
+    (recv_time << 20) | thread_rng().gen_range(0..1 << 20)
+}
+
+/// An order for `place_order`.  Prices and amounts are 1e18 fixed point.
+#[derive(Debug, Clone, Copy)]
+pub struct OrderParams {
+    pub product_id: ProductId,
+    pub price_x18: i128,
+    pub amount: i128,    // positive to buy, negative to sell
+    pub expiration: u64, // unix seconds; the top bits select the order type (IOC, FOK, post-only), see `order_expiration`
+    pub nonce: u64,      // see `order_nonce`
+}
+
+#[derive(Debug)]
+pub enum OrderError {
+    Sign(String),
+    Query(QueryError),
+}
+
+impl fmt::Display for OrderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OrderError::Sign(e) => write!(f, "Failed to sign order: {}", e),
+            OrderError::Query(e) => write!(f, "Failed to place order: {}", e),
+        }
+    }
 }
 
+impl std::error::Error for OrderError {}
 
-fn _authenticate_message(sender: &str, expiration: u64, signature: &str) -> String {
+fn authenticate_message(id: u64, sender: &Subaccount, expiration: u64, signature: &str) -> String {
     json!({
       "method": "authenticate",
-      "id": 0,
+      "id": id,
       "tx": {
-        "sender": format!("0x{}", sender),
+        "sender": sender,
         "expiration": expiration.to_string()
       },
       "signature": signature.to_string()
@@ -195,97 +1202,345 @@ fn _generate_wallet() -> (String, Address) {
 }
 
 
-async fn _authenticate(url: String) {
-    let connection = connect_async_with_config(
-        url,
-        Some(WebSocketConfig {
-            compression: Some(DeflateConfig::default()),
-            ..WebSocketConfig::default()
-        }),
-    )
-        .await;
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SenderError {
+    SubaccountTooLong { name: String, len: usize }, // subaccount names are at most 12 bytes
+    InvalidAddress { address: String },             // not a 20-byte hex address
+    InvalidLength { len: usize },                   // a sender is exactly 32 bytes
+}
 
-    if let Err(e) = connection {
-        println!("failed to connect: {}", e);
-        return;
+impl fmt::Display for SenderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SenderError::SubaccountTooLong { name, len } => {
+                write!(f, "Subaccount name {:?} is {} bytes, max is 12", name, len)
+            }
+            SenderError::InvalidAddress { address } => {
+                write!(f, "Invalid address {:?}: expected 20 hex-encoded bytes", address)
+            }
+            SenderError::InvalidLength { len } => {
+                write!(f, "Sender is {} bytes, expected 32", len)
+            }
+        }
     }
+}
 
-    let (private_key, address) = _generate_wallet();
-    let address_hex = format!("{:#x}", address);
-    let subaccount = "64656661756c740000000000"; // "default"
+impl std::error::Error for SenderError {}
 
-    println!("Generated Private Key: {}", private_key);
-    println!("Generated Address: {}", address_hex);
-    println!("Subaccount: {}", subaccount);
+/// UTF-8 subaccount name right-padded with zeros to 12 bytes, e.g. "default" -> 64656661756c740000000000
+pub fn encode_subaccount(name: &str) -> Result<[u8; 12], SenderError> {
+    let bytes = name.as_bytes();
+    if bytes.len() > 12 {
+        return Err(SenderError::SubaccountTooLong { name: name.to_string(), len: bytes.len() });
+    }
 
-    // Concatenate the address and subaccount
-    let sender_hex = format!("{}{}", &address_hex[2..], subaccount); // Remove "0x" from address
+    let mut subaccount = [0u8; 12];
+    subaccount[..bytes.len()].copy_from_slice(bytes);
+    Ok(subaccount)
+}
 
-    // Decode the concatenated hex string into bytes
-    let sender_bytes = hex::decode(sender_hex.clone()).expect("Invalid concatenated hex string");
+/// 32-byte Vertex sender: 20-byte address followed by the 12-byte subaccount
+pub fn build_sender(address: Address, subaccount: [u8; 12]) -> Subaccount {
+    Subaccount::new(address, subaccount)
+}
 
-    // Convert the decoded bytes into a fixed-size array [u8; 32]
-    let sender_bz: [u8; 32] = sender_bytes
-        .try_into()
-        .expect("Sender must be exactly 32 bytes");
+/// Sender from a hex address (with or without `0x`) and a subaccount name, e.g. ("0xabc...", "default")
+pub fn parse_sender(address: &str, subaccount: &str) -> Result<Subaccount, SenderError> {
+    let address = address
+        .parse::<Address>()
+        .map_err(|_| SenderError::InvalidAddress { address: address.to_string() })?;
+    Ok(build_sender(address, encode_subaccount(subaccount)?))
+}
 
+/// Sender from its raw bytes, e.g. as decoded from a hex string
+pub fn sender_from_bytes(bytes: &[u8]) -> Result<Subaccount, SenderError> {
+    let sender: [u8; 32] = bytes.try_into().map_err(|_| SenderError::InvalidLength { len: bytes.len() })?;
+    Ok(Subaccount(sender))
+}
 
-    let expiration = _get_expiration() as u64;
+#[derive(Debug)]
+pub enum AuthError {
+    Sign(String),
+    Send(tungstenite::Error),
+    Receive(tungstenite::Error),
+    Closed,
+    Parse(serde_json::Error),
+    Rejected(String),
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::Sign(e) => write!(f, "Failed to sign authentication: {}", e),
+            AuthError::Send(e) => write!(f, "Failed to send authentication: {}", e),
+            AuthError::Receive(e) => write!(f, "Failed to receive authentication response: {}", e),
+            AuthError::Closed => write!(f, "WebSocket closed before authentication was confirmed"),
+            AuthError::Parse(e) => write!(f, "Failed to parse authentication response: {}", e),
+            AuthError::Rejected(e) => write!(f, "Authentication rejected: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+impl AuthError {
+    /// The connection failed mid-handshake; authenticating again on a fresh connection may succeed
+    pub fn is_transient(&self) -> bool {
+        matches!(self, AuthError::Send(_) | AuthError::Receive(_) | AuthError::Closed)
+    }
+}
+
+/// Authenticate `ws` as `wallet`'s `subaccount` (see `encode_subaccount`) so authenticated streams
+/// can be subscribed to on the same socket.  The signed request is valid for `valid_for`, see `auth_expiration`.
+/// Returns once the gateway has acknowledged the request.
+pub async fn authenticate<S: WsConnection>(
+    ws: &mut S,
+    wallet: &LocalWallet,
+    subaccount: [u8; 12],
+    network: Network,
+    valid_for: Duration,
+) -> Result<(), AuthError> {
+    let sender = build_sender(wallet.address(), subaccount);
+
+    let expiration = auth_expiration(valid_for);
     let stream_auth = StreamAuthentication {
-        sender: sender_bz,
+        sender: sender.0,
         expiration,
     };
 
-    let signature = _generate_eip712_signature(stream_auth, private_key.as_str());
-    let (mut ws, _) = connection.unwrap();
-    let sub = _authenticate_message(
-        sender_hex.as_str(),
+    let signature = generate_eip712_signature(stream_auth, wallet, network)?;
+    let id = next_request_id();
+    let message = authenticate_message(
+        id,
+        &sender,
         expiration,
         signature.as_str(),
     );
-    println!("{:?}", sub);
-    if let Err(e) = ws.send(Message::Text(sub.into())).await {
-        println!("Failed to send message: {}", e);
-        return;
-    }
+    ws.send(Message::Text(message)).await.map_err(AuthError::Send)?;
 
+    // wait for the ack, skipping control frames and anything that isn't the reply to this request
     loop {
-        select! {
-            message = ws.next() => {
-                match message {
-                    Some(Ok(msg)) => {
-                        if msg.is_text() {
-                            let text = msg.into_text().unwrap();
-                            println!("{text}");
-                        }
-                    }
-                    _ => {
-                        println!("WebSocket closed");
-                        break;
-                    }
-                }
-            }
+        match ws.next().await {
+            Some(Ok(Message::Text(text))) => match authenticate_reply(&text, id) {
+                Some(result) => return result,
+                None => continue,
+            },
+            Some(Ok(_)) => continue,
+            Some(Err(e)) => return Err(AuthError::Receive(e)),
+            None => return Err(AuthError::Closed),
         }
     }
 }
 
-fn _generate_eip712_signature(stream_authentication: StreamAuthentication, private_key: &str) -> String {
-    let domain = EIP712Domain {
-        name: Some("Vertex".to_string()),
-        version: Some("0.0.1".to_string()),
-        chain_id: Some(U256::from(42161)),
-        verifying_contract: Some(Address::from_str("0xbbEE07B3e8121227AfCFe1E2B82772246226128e")
-            .expect("Invalid address")),
-        salt: None,
+// The outcome of the `authenticate` request `id` if `text` is its reply (or isn't JSON at all), None if it's some
+// other frame
+fn authenticate_reply(text: &str, id: u64) -> Option<Result<(), AuthError>> {
+    let resp: serde_json::Value = match serde_json::from_str(text) {
+        Ok(resp) => resp,
+        Err(e) => return Some(Err(AuthError::Parse(e))),
+    };
+    if resp.get("id").and_then(serde_json::Value::as_u64) != Some(id) {
+        return None;
+    }
+    match resp.get("error") {
+        Some(error) => Some(Err(AuthError::Rejected(error.to_string()))),
+        None => Some(Ok(())),
+    }
+}
+
+fn generate_eip712_signature(
+    stream_authentication: StreamAuthentication,
+    wallet: &LocalWallet,
+    network: Network,
+) -> Result<String, AuthError> {
+    network.signing_domain().sign(&stream_authentication, wallet).map_err(AuthError::Sign)
+}
+
+fn generate_order_signature(
+    sender: &Subaccount,
+    order: &OrderParams,
+    wallet: &LocalWallet,
+    domain: &SigningDomain,
+) -> Result<String, OrderError> {
+    let order = Order {
+        sender: sender.0,
+        price_x18: order.price_x18,
+        amount: order.amount,
+        expiration: order.expiration,
+        nonce: order.nonce,
     };
+    domain.sign(&order, wallet).map_err(OrderError::Sign)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MockTransport;
+
+    const ADDRESS: &str = "0xf39fd6e51aad88f6f4ce6ab8827279cfffb92266";
+
+    #[test]
+    fn default_subaccount_encoding() {
+        assert_eq!(encode(encode_subaccount("default").unwrap()), "64656661756c740000000000");
+    }
+
+    #[test]
+    fn empty_subaccount_is_all_zeros() {
+        assert_eq!(encode_subaccount(""), Ok([0u8; 12]));
+    }
+
+    #[test]
+    fn short_subaccount_is_zero_padded() {
+        assert_eq!(encode_subaccount("ab"), Ok([b'a', b'b', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn twelve_byte_subaccount_fills_the_name() {
+        assert_eq!(encode_subaccount("abcdefghijkl"), Ok(*b"abcdefghijkl"));
+    }
+
+    #[test]
+    fn thirteen_byte_subaccount_is_rejected() {
+        let too_long = SenderError::SubaccountTooLong { name: "abcdefghijklm".to_string(), len: 13 };
+        assert_eq!(encode_subaccount("abcdefghijklm"), Err(too_long.clone()));
+        assert_eq!(parse_sender(ADDRESS, "abcdefghijklm"), Err(too_long));
+    }
+
+    #[test]
+    fn parse_sender_puts_the_address_before_the_name() {
+        let sender = parse_sender(ADDRESS, "ab").unwrap();
+        assert_eq!(sender.address(), ADDRESS.parse::<Address>().unwrap());
+        assert_eq!(&sender.as_bytes()[20..], &[b'a', b'b', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(parse_sender(&ADDRESS[2..], "ab"), Ok(sender)); // 0x is optional
+    }
+
+    #[test]
+    fn parse_sender_rejects_an_invalid_address() {
+        let invalid = SenderError::InvalidAddress { address: "0x1234".to_string() };
+        assert_eq!(parse_sender("0x1234", "default"), Err(invalid));
+    }
 
-    let domain_separator = domain.separator();
-    let struct_hash = stream_authentication.struct_hash().unwrap();
-    let digest_input = [&[0x19, 0x01], &domain_separator[..], &struct_hash[..]].concat();
-    let digest_hash = H256::from(keccak256(digest_input));
+    #[test]
+    fn sender_from_bytes_requires_32_bytes() {
+        let sender = parse_sender(ADDRESS, "").unwrap();
+        assert_eq!(sender_from_bytes(sender.as_bytes()), Ok(sender));
+        assert_eq!(sender_from_bytes(&[0u8; 31]), Err(SenderError::InvalidLength { len: 31 }));
+        assert_eq!(sender_from_bytes(&[0u8; 33]), Err(SenderError::InvalidLength { len: 33 }));
+    }
 
-    // sign hash
-    let wallet: LocalWallet = private_key.parse().expect("Invalid private key");
-    let signature = wallet.sign_hash(digest_hash).expect("Failed to sign hash");
-    format!("0x{}", signature)
-}
\ No newline at end of file
+    fn book_depth(product_id: u32, last_max_timestamp: u128, max_timestamp: u128) -> String {
+        format!(
+            r#"{{"type":"book_depth","min_timestamp":"{}","max_timestamp":"{}","last_max_timestamp":"{}","product_id":{},"bids":[],"asks":[]}}"#,
+            last_max_timestamp + 1, max_timestamp, last_max_timestamp, product_id
+        )
+    }
+
+    fn trade(product_id: u32, timestamp: u128, price: u128) -> String {
+        format!(
+            r#"{{"type":"trade","timestamp":"{}","product_id":{},"price":"{}","taker_qty":"1","is_taker_buyer":true}}"#,
+            timestamp, product_id, price
+        )
+    }
+
+    // What the current connection delivered, as (stream type, product, timestamp) with no frames
+    fn delivered(streams: &[(&str, u32, u128)]) -> HashMap<StreamKey, Delivered> {
+        streams
+            .iter()
+            .map(|&(stream_type, product_id, timestamp)| {
+                let key = (stream_type.to_string(), Some(ProductId(product_id)));
+                (key, Delivered { timestamp, frames: Vec::new() })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn replacement_overlaps_once_every_product_has_caught_up() {
+        let mut replacement = Replacement::new((), Vec::new(), Duration::from_secs(1));
+        replacement.hold(r#"{"result":null,"id":1}"#.to_string()); // subscribe ack
+        replacement.hold(book_depth(2, 10, 11));
+        replacement.hold(book_depth(2, 11, 12));
+        replacement.hold(book_depth(4, 7, 8));
+
+        // the current connection is behind product 2's first held event: 10 would be lost in between
+        assert!(!replacement.overlaps(&delivered(&[("book_depth", 2, 9), ("book_depth", 4, 8)])));
+        // it has delivered up to the event product 2's held events continue from
+        assert!(replacement.overlaps(&delivered(&[("book_depth", 2, 10), ("book_depth", 4, 8)])));
+        // no held event for product 6 yet
+        assert!(!replacement.overlaps(&delivered(&[("book_depth", 2, 12), ("book_depth", 6, 3)])));
+    }
+
+    #[test]
+    fn replacement_overlaps_on_every_stream_not_just_book_depth() {
+        let mut replacement = Replacement::new((), Vec::new(), Duration::from_secs(1));
+        replacement.hold(r#"{"result":null,"id":1}"#.to_string()); // subscribe ack
+        // trades delivered but none held yet: an ack alone doesn't prove nothing was missed
+        assert!(!replacement.overlaps(&delivered(&[("trade", 2, 5)])));
+
+        replacement.hold(trade(2, 5, 100));
+        // other trades at 5 may have come before the first held one, and only the current connection saw them
+        assert!(!replacement.overlaps(&delivered(&[("trade", 2, 5)])));
+        assert!(replacement.overlaps(&delivered(&[("trade", 2, 6)])));
+        // unless the first held one was delivered too: anything before it at 5 was delivered
+        let mut last_delivered = HashMap::new();
+        record_delivered(&mut last_delivered, ("trade".to_string(), Some(ProductId(2))), 5, trade(2, 5, 100));
+        assert!(replacement.overlaps(&last_delivered));
+        record_delivered(&mut last_delivered, ("trade".to_string(), Some(ProductId(2))), 5, trade(2, 5, 101));
+        assert!(replacement.overlaps(&last_delivered));
+    }
+
+    #[test]
+    fn already_delivered_book_depth_is_dropped() {
+        let last_delivered = delivered(&[("book_depth", 2, 11)]);
+        assert!(already_delivered(&book_depth(2, 10, 11), &last_delivered));
+        assert!(!already_delivered(&book_depth(2, 11, 12), &last_delivered));
+        assert!(!already_delivered(&book_depth(4, 1, 2), &last_delivered)); // not delivered for this product
+        assert!(!already_delivered(r#"{"result":null,"id":1}"#, &last_delivered));
+    }
+
+    #[test]
+    fn already_delivered_trades_are_dropped() {
+        let mut last_delivered = HashMap::new();
+        record_delivered(&mut last_delivered, ("trade".to_string(), Some(ProductId(2))), 4, trade(2, 4, 99));
+        record_delivered(&mut last_delivered, ("trade".to_string(), Some(ProductId(2))), 5, trade(2, 5, 100));
+
+        assert!(already_delivered(&trade(2, 4, 99), &last_delivered));
+        assert!(already_delivered(&trade(2, 5, 100), &last_delivered));
+        assert!(!already_delivered(&trade(2, 5, 101), &last_delivered)); // another trade at the same timestamp
+        assert!(!already_delivered(&trade(2, 6, 100), &last_delivered));
+        assert!(!already_delivered(&trade(4, 4, 99), &last_delivered)); // not delivered for this product
+    }
+
+    #[tokio::test]
+    async fn rejected_subscription_is_dropped_and_the_others_keep_running() {
+        let config = Config { max_consecutive_failures: Some(1), ..Config::default() }; // stop once the script ends
+        let rejected = r#"{"method":"subscribe","stream":{"type":"trade","product_id":99},"id":901}"#;
+        let accepted = r#"{"method":"subscribe","stream":{"type":"trade","product_id":2},"id":902}"#;
+        let subscriptions = Subscriptions::new(vec![rejected.to_string(), accepted.to_string()]);
+        let rejection = r#"{"error":"invalid product_id","id":901,"error_code":1}"#;
+        let transport = MockTransport::from_text(vec![vec![rejection, trade(2, 5, 100).as_str()]]);
+
+        let (sender, mut receiver) = mpsc::channel(10);
+        let result = subscribe_with_transport(&transport, sender, subscriptions.clone(), &config, None, Arc::new(Stats::default())).await;
+
+        assert!(matches!(result, Err(SubscribeError::TooManyFailures { .. }))); // not rejected: it ran until the script ended
+        assert!(matches!(
+            receiver.recv().await,
+            Some(StreamResponseType::SubscriptionResponse(SubscriptionResult::Error(e))) if e.id == Some(901)
+        ));
+        assert!(matches!(receiver.recv().await, Some(StreamResponseType::Trade(trade)) if trade.product_id == ProductId(2)));
+        assert_eq!(subscriptions.frames(), vec![accepted.to_string()]);
+    }
+
+    #[test]
+    fn only_the_reply_with_the_authenticate_id_is_its_ack() {
+        assert!(matches!(authenticate_reply(r#"{"result":null,"id":7}"#, 7), Some(Ok(()))));
+        assert!(matches!(
+            authenticate_reply(r#"{"error":"invalid signature","id":7,"error_code":2}"#, 7),
+            Some(Err(AuthError::Rejected(_)))
+        ));
+        // a reply to another request, or an event, doesn't settle it either way
+        assert!(authenticate_reply(r#"{"error":"invalid product_id","id":3,"error_code":1}"#, 7).is_none());
+        assert!(authenticate_reply(&trade(2, 5, 100), 7).is_none());
+        assert!(matches!(authenticate_reply("not json", 7), Some(Err(AuthError::Parse(_)))));
+    }
+}
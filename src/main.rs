@@ -1,106 +1,106 @@
-#![allow(non_snake_case)]
-
-mod model;
-mod listener;
-
-use serde_json::json;
+use std::env;
+use std::io::IsTerminal;
+use std::time::Duration;
+use tracing::error;
+use tracing_subscriber::EnvFilter;
 use tokio::sync::mpsc;
-use tokio::sync::mpsc::Receiver;
-use listener::Subscribe;
-use model::StreamResponseType;
-use crate::listener::QueryMarketLiquidity;
-use crate::model::{MarketLiquidityResponse, OrderBook};
-
-const SUBSCRIPTION_URL: &str = "wss://gateway.prod.vertexprotocol.com/v1/subscribe";
-const GATEWAY_URL: &str = "wss://gateway.prod.vertexprotocol.com/v1/ws";
-const PRODUCT_ID: usize = 2; // BTC-USDC perp
-const BOOK_DEPTH_STREAM_BUFFER_SIZE: usize = 1000000; // 1MM
-const MARKET_LIQ_QUERY_DEPTH: usize = 10; // how deep to fill the order book up from snapshot (max 100)
-const PING_FRAME_INTERVAL: u64 = 5; // how often to send ping frames to keep the ws connection alive (max 30)
+use lhava_vertex_rust::builder::{JsonObserver, OrderBookBuilder, PrintObserver};
+use lhava_vertex_rust::config::{Config, ConfigError};
+use lhava_vertex_rust::listener::{subscribe_with_transport, Subscriptions};
+use lhava_vertex_rust::model::StreamResponseType;
+use lhava_vertex_rust::listener::GatewayClient;
+use lhava_vertex_rust::model::{DisplayPrecision, OrderBook, ValidationMode};
+use lhava_vertex_rust::replay::replay_from;
+use lhava_vertex_rust::request::SubscriptionRequest;
+use lhava_vertex_rust::symbols::SymbolRegistry;
+use lhava_vertex_rust::transport::{TungsteniteTransport, WsTransport};
+use lhava_vertex_rust::Result;
+
+const PRICE_DECIMALS: u32 = 18; // fixed-point decimals of the product's prices
+const SIZE_DECIMALS: u32 = 18; // fixed-point decimals of the product's quantities
+const VALIDATION_MODE: ValidationMode = ValidationMode::Strict; // what to do when the book fails validation
+const FULL_VALIDATION: bool = cfg!(debug_assertions); // per-level checks after every update; release builds skip them
+const DISPLAY_LEVELS: usize = 20; // levels rendered per side
+const DISPLAY_PRECISION: DisplayPrecision = DisplayPrecision { price: 2, size: 4 }; // decimals rendered for prices / quantities
 
 #[tokio::main]
-async fn main() {
+async fn main() -> Result<()> {
+    // logs go to stderr so they don't tear the book rendered on stdout; filter with RUST_LOG
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .with_writer(std::io::stderr)
+        .init();
+
+    let config = Config::from_env()?;
+
+    match env::var("VERTEX_REPLAY_FROM") {
+        // replay a recording made with VERTEX_RECORD_TO instead of connecting
+        Ok(path) => {
+            let speed = match env::var("VERTEX_REPLAY_SPEED") {
+                Ok(value) => Some(value.parse().map_err(|_| ConfigError { var: "VERTEX_REPLAY_SPEED", value })?),
+                Err(_) => None,
+            };
+            let replay = replay_from(&path, &config, speed)?;
+            let gateway = GatewayClient::with_transport(
+                replay.gateway,
+                &config.gateway_url,
+                config.backoff,
+                0,
+                Duration::from_secs(config.query_timeout),
+                0.0,
+            );
+            // the stream ends with the recording: don't reconnect once it's exhausted
+            let config = Config { max_consecutive_failures: Some(1), record_to: None, ..config };
+            run(config, gateway, replay.subscription).await
+        }
+        Err(_) => {
+            let gateway = GatewayClient::from_config(&config);
+            let transport = TungsteniteTransport::from_config(&config);
+            run(config, gateway, transport).await
+        }
+    }
+}
+
+async fn run<G: WsTransport, S: WsTransport + 'static>(config: Config, gateway: GatewayClient<G>, transport: S) -> Result<()> {
+    let mut order_book = OrderBook::new(PRICE_DECIMALS, SIZE_DECIMALS)?;
+    order_book.set_display_precision(DISPLAY_PRECISION);
+    order_book.set_full_validation(FULL_VALIDATION);
+    let symbols = SymbolRegistry::for_network(config.network);
+    order_book.set_symbol(symbols.symbol_for(config.product_id).map(str::to_string));
+    // color the table only when it's drawn on a terminal, unless told otherwise
+    order_book.set_color(match env::var("VERTEX_COLOR") {
+        Ok(value) => value.parse().map_err(|_| ConfigError { var: "VERTEX_COLOR", value })?,
+        Err(_) => std::io::stdout().is_terminal(),
+    });
+    let mut builder = OrderBookBuilder::new(
+        gateway,
+        order_book,
+        config.product_id,
+        config.market_liq_query_depth,
+        VALIDATION_MODE,
+    )?;
+    builder.set_backpressure(config.backpressure);
+    builder.set_recent_events(config.recent_events);
 
     // listen to the book_depth stream
     let (sender, receiver) =
-        mpsc::channel::<StreamResponseType>(BOOK_DEPTH_STREAM_BUFFER_SIZE);
-    tokio::spawn(async move { Subscribe(sender, &book_depth(), &SUBSCRIPTION_URL).await; });
+        mpsc::channel::<StreamResponseType>(config.book_depth_stream_buffer_size);
+    let subscribe_config = config.clone();
+    let stats = builder.stats();
+    tokio::spawn(async move {
+        let subscriptions = Subscriptions::new(vec![SubscriptionRequest::book_depth(subscribe_config.product_id).into()]);
+        if let Err(e) = subscribe_with_transport(&transport, sender, subscriptions, &subscribe_config, None, stats).await {
+            error!(error = %e, "subscription stopped");
+        }
+    });
 
     // build + display order book
-    build_orderbook(receiver).await;
-
-}
-
-async fn build_orderbook(mut receiver: Receiver<StreamResponseType>) {
-    // From the docs: https://docs.vertexprotocol.com/developer-resources/api/subscriptions/events#book-depth
-    //
-    // To keep an updated local orderbook, do the following:
-    // 1. Subscribe to the book_depth stream and queue up events.
-    // 2. Get a market data snapshot by calling MarketLiquidity. The snapshot contains a timestamp in the response
-    // 3. Apply events with max_timestamp > snapshot timestamp.
-    // 4. When you receive an event where its last_max_timestamp is not equal to the last event you've received,
-    //    it means some events were lost and you should repeat 1-3 again.
-
-    let mut order_book = OrderBook::new();
-
-    // snapshot_timestamp is used to track if we missed events
-    let snapshot = query_market_liquidity().await;
-    let mut snapshot_timestamp: u128 = snapshot.data.timestamp.parse().expect("expected u128");
-    let mut prev_timestamp = None;
-
-    // populate the order book
-    order_book.from_snapshot(snapshot);
-
-    while let Some(event) = receiver.recv().await {
-        match event {
-            StreamResponseType::BookDepth(data) => {
-                let last_max_timestamp: u128 = data.last_max_timestamp.parse().expect("last max timestamp");
-                let max_timestamp: u128 = data.max_timestamp.parse().expect("max timestamp");
-
-                if last_max_timestamp <= snapshot_timestamp {
-                    continue // drop msgs from before the snapshot
-                }
-
-                if prev_timestamp.is_none() || prev_timestamp == Some(last_max_timestamp) {
-                    prev_timestamp = Some(max_timestamp);
-                    order_book.update(data);
-                    print!("{}", order_book.visualize());
-                } else {
-                    println!("dropped a book depth update, retrieving snapshot...");
-                    // populate from the snapshot response
-                    let snapshot = query_market_liquidity().await;
-                    snapshot_timestamp = snapshot.data.timestamp.parse().expect("snapshot timestamp");
-                    order_book.from_snapshot(snapshot);
-
-                }
-            }
-            _ => {}
+    match env::var("VERTEX_OUTPUT").as_deref() {
+        Ok("json") => builder.run(receiver, JsonObserver { levels: DISPLAY_LEVELS }).await,
+        _ => {
+            let render_interval = Duration::from_millis(config.render_interval);
+            builder.run(receiver, PrintObserver::new(DISPLAY_LEVELS, None, render_interval)).await
         }
-
     }
-
-}
-
-
-fn book_depth() -> String {
-    json!({
-        "method": "subscribe",
-        "stream": {
-           "type": "book_depth",
-           "product_id": PRODUCT_ID
-        },
-        "id": 0
-    })
-        .to_string()
-}
-
-async fn query_market_liquidity() -> MarketLiquidityResponse {
-    let market_liquidity_request = json!({
-      "type": "market_liquidity",
-      "product_id": PRODUCT_ID,
-      "depth": MARKET_LIQ_QUERY_DEPTH
-    })
-    .to_string();
-
-    QueryMarketLiquidity(&market_liquidity_request, GATEWAY_URL).await
+    Ok(())
 }
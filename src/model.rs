@@ -1,41 +1,368 @@
-use serde::{de, Deserialize, Deserializer};
-use std::collections::BTreeMap;
+use ethers::types::{Address, U256};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use serde_json::json;
+use serde_with::{serde_as, DisplayFromStr};
+use std::collections::{btree_map, BTreeMap, BTreeSet, HashSet};
+use std::fmt;
+use std::mem;
+use std::str::FromStr;
 
 /// Internal
 
 //
-#[derive(Debug, Deserialize)]
-#[serde(untagged)]
+// Events are routed on their `type` field.  Subscription acks carry no `type`, so they are
+// parsed separately in `from_json`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type")]
 #[allow(dead_code)]
 pub enum StreamResponseType {
+    #[serde(rename = "book_depth")]
     BookDepth(BookDepthResponse),
-    SubscriptionResponse(SubscriptionResponse)
+    #[serde(rename = "trade")]
+    Trade(TradeResponse),
+    #[serde(rename = "best_bid_offer")]
+    BestBidOffer(BestBidOfferResponse),
+    #[serde(rename = "fill")]
+    Fill(FillResponse), // requires an authenticated connection
+    #[serde(rename = "position_change")]
+    PositionChange(PositionChangeResponse), // requires an authenticated connection
+    #[serde(rename = "market_price")]
+    MarketPrice(MarketPriceResponse),
+    #[serde(rename = "latest_candlestick", alias = "candlestick")]
+    Candlestick(CandlestickResponse),
+    #[serde(rename = "liquidation")]
+    Liquidation(LiquidationResponse),
+    #[serde(skip_deserializing)]
+    SubscriptionResponse(SubscriptionResult),
+    // Emitted by `Subscribe` once a dropped connection is re-established and resubscribed.
+    // Events sent while disconnected are lost, so anything built from the stream is stale until resnapshotted.
+    #[serde(skip_deserializing)]
+    Reconnected,
     // ...register more stream response models here
+    #[serde(other)]
+    Unknown,
+}
+
+impl StreamResponseType {
+    pub fn from_json(text: &str) -> serde_json::Result<Self> {
+        serde_json::from_str::<StreamResponseType>(text).or_else(|e| {
+            serde_json::from_str::<SubscriptionResult>(text)
+                .map(StreamResponseType::SubscriptionResponse)
+                .map_err(|_| e) // report why it wasn't a valid event
+        })
+    }
+
+    /// `type` of the stream the event came from, as named in subscribe frames.  None for acks and synthetic events.
+    pub fn stream_type(&self) -> Option<&'static str> {
+        match self {
+            StreamResponseType::BookDepth(_) => Some("book_depth"),
+            StreamResponseType::Trade(_) => Some("trade"),
+            StreamResponseType::BestBidOffer(_) => Some("best_bid_offer"),
+            StreamResponseType::Fill(_) => Some("fill"),
+            StreamResponseType::PositionChange(_) => Some("position_change"),
+            StreamResponseType::MarketPrice(_) => Some("market_price"),
+            StreamResponseType::Candlestick(_) => Some("latest_candlestick"),
+            StreamResponseType::Liquidation(_) => Some("liquidation"),
+            StreamResponseType::SubscriptionResponse(_)
+            | StreamResponseType::Reconnected
+            | StreamResponseType::Unknown => None,
+        }
+    }
+
+    /// Product the event belongs to, if it is product-scoped
+    pub fn product_id(&self) -> Option<ProductId> {
+        match self {
+            StreamResponseType::BookDepth(data) => Some(data.product_id),
+            StreamResponseType::Trade(data) => Some(data.product_id),
+            StreamResponseType::BestBidOffer(data) => Some(data.product_id),
+            StreamResponseType::Fill(data) => Some(data.product_id),
+            StreamResponseType::PositionChange(data) => Some(data.product_id),
+            StreamResponseType::MarketPrice(data) => Some(data.product_id),
+            StreamResponseType::Candlestick(data) => Some(data.product_id),
+            StreamResponseType::Liquidation(data) => Some(data.product_id),
+            StreamResponseType::SubscriptionResponse(_)
+            | StreamResponseType::Reconnected
+            | StreamResponseType::Unknown => None,
+        }
+    }
 }
 
 /// Vertex
 
-#[derive(Debug, Deserialize)]
+/// Vertex product id, e.g. 2 for the BTC-USDC perp.  Serializes as the bare number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ProductId(pub u32);
+
+impl From<u32> for ProductId {
+    fn from(id: u32) -> Self {
+        ProductId(id)
+    }
+}
+
+impl fmt::Display for ProductId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for ProductId {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(ProductId)
+    }
+}
+
+/// 32-byte Vertex sender: 20-byte address followed by the 12-byte subaccount name.
+/// Serializes as 0x-prefixed hex, the way the gateway sends and expects it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Subaccount(pub [u8; 32]);
+
+impl Subaccount {
+    /// `name` is the zero-padded subaccount name, see `listener::encode_subaccount`
+    pub fn new(address: Address, name: [u8; 12]) -> Self {
+        let mut sender = [0u8; 32];
+        sender[..20].copy_from_slice(address.as_bytes());
+        sender[20..].copy_from_slice(&name);
+        Subaccount(sender)
+    }
+
+    pub fn address(&self) -> Address {
+        Address::from_slice(&self.0[..20])
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl From<[u8; 32]> for Subaccount {
+    fn from(sender: [u8; 32]) -> Self {
+        Subaccount(sender)
+    }
+}
+
+impl fmt::Display for Subaccount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "0x{}", hex::encode(self.0))
+    }
+}
+
+impl FromStr for Subaccount {
+    type Err = hex::FromHexError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut sender = [0u8; 32];
+        hex::decode_to_slice(s.trim_start_matches("0x"), &mut sender)?;
+        Ok(Subaccount(sender))
+    }
+}
+
+impl Serialize for Subaccount {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Subaccount {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value: String = Deserialize::deserialize(deserializer)?;
+        value.parse().map_err(de::Error::custom)
+    }
+}
+
+/// Gateway reply to a subscribe/unsubscribe/authenticate request
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum SubscriptionResult {
+    Error(SubscriptionError), // tried first: only rejections carry `error`
+    Ok(SubscriptionResponse),
+}
+
+#[derive(Debug, Clone, Deserialize)]
 #[allow(dead_code)]
 pub struct SubscriptionResponse {
     pub result: Option<serde_json::Value>,
     pub id: u64,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
+#[allow(dead_code)]
+pub struct SubscriptionError {
+    pub error: String,
+    pub id: Option<u64>,
+    pub error_code: Option<i64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
 #[allow(dead_code)]
 pub struct BookDepthResponse {
-    pub r#type: String, // `type` is a reserved keyword in Rust
-    pub min_timestamp: String,
-    pub max_timestamp: String,
-    pub last_max_timestamp: String,
-    pub product_id: u32,
+    #[serde(deserialize_with = "deserialize_u128_str")]
+    pub min_timestamp: u128,
+    #[serde(deserialize_with = "deserialize_u128_str")]
+    pub max_timestamp: u128,
+    #[serde(deserialize_with = "deserialize_u128_str")]
+    pub last_max_timestamp: u128,
+    pub product_id: ProductId,
     #[serde(deserialize_with = "deserialize_bid_ask")]
     pub bids: Vec<(u128, u128)>, // (bid price, quantity)
     #[serde(deserialize_with = "deserialize_bid_ask")]
     pub asks: Vec<(u128, u128)>,
 }
 
+impl BookDepthResponse {
+    /// Build an event from in-memory levels, e.g. to replay or test updates without the network
+    pub fn new(
+        last_max_timestamp: u128,
+        max_timestamp: u128,
+        product_id: ProductId,
+        bids: Vec<(u128, u128)>,
+        asks: Vec<(u128, u128)>,
+    ) -> Self {
+        BookDepthResponse {
+            min_timestamp: last_max_timestamp + 1,
+            max_timestamp,
+            last_max_timestamp,
+            product_id,
+            bids,
+            asks,
+        }
+    }
+
+    /// (min_timestamp, max_timestamp, last_max_timestamp) as the decimal strings Vertex sends
+    pub fn raw_timestamps(&self) -> (String, String, String) {
+        (
+            self.min_timestamp.to_string(),
+            self.max_timestamp.to_string(),
+            self.last_max_timestamp.to_string(),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[allow(dead_code)]
+pub struct TradeResponse {
+    pub timestamp: String,
+    pub product_id: ProductId,
+    #[serde(deserialize_with = "deserialize_u128_str")]
+    pub price: u128,
+    #[serde(deserialize_with = "deserialize_u128_str")]
+    pub taker_qty: u128,
+    pub is_taker_buyer: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[allow(dead_code)]
+pub struct BestBidOfferResponse {
+    pub timestamp: String,
+    pub product_id: ProductId,
+    #[serde(deserialize_with = "deserialize_u128_str")]
+    pub bid_price: u128,
+    #[serde(deserialize_with = "deserialize_u128_str")]
+    pub bid_qty: u128,
+    #[serde(deserialize_with = "deserialize_u128_str")]
+    pub ask_price: u128,
+    #[serde(deserialize_with = "deserialize_u128_str")]
+    pub ask_qty: u128,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[allow(dead_code)]
+pub struct FillResponse {
+    pub timestamp: String,
+    pub product_id: ProductId,
+    pub subaccount: Subaccount,
+    pub order_digest: String,
+    #[serde(deserialize_with = "deserialize_u128_str")]
+    pub filled_qty: u128,
+    #[serde(deserialize_with = "deserialize_u128_str")]
+    pub price: u128,
+    pub is_bid: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[allow(dead_code)]
+pub struct PositionChangeResponse {
+    pub timestamp: String,
+    pub product_id: ProductId,
+    pub subaccount: Subaccount,
+    #[serde(default)]
+    pub is_lp: bool,
+    #[serde(deserialize_with = "deserialize_i128_str")]
+    pub amount: i128, // signed position size, negative when short
+    #[serde(deserialize_with = "deserialize_i128_str")]
+    pub v_quote_amount: i128,
+}
+
+impl PositionChangeResponse {
+    pub fn is_long(&self) -> bool {
+        self.amount > 0
+    }
+}
+
+/// Oracle (index) and mark price of a perp, the inputs to its funding rate
+#[derive(Debug, Clone, Deserialize)]
+#[allow(dead_code)]
+pub struct MarketPriceResponse {
+    pub product_id: ProductId,
+    #[serde(deserialize_with = "deserialize_u128_str")]
+    pub index_price: u128,
+    #[serde(deserialize_with = "deserialize_u128_str")]
+    pub mark_price: u128,
+    pub update_time: String,
+}
+
+/// The candle currently forming for `granularity`, resent as it updates
+#[derive(Debug, Clone, Deserialize)]
+#[allow(dead_code)]
+pub struct CandlestickResponse {
+    pub timestamp: String,
+    pub product_id: ProductId,
+    pub granularity: u32, // candle width in seconds
+    #[serde(alias = "open_x18", deserialize_with = "deserialize_u128_str")]
+    pub open: u128,
+    #[serde(alias = "high_x18", deserialize_with = "deserialize_u128_str")]
+    pub high: u128,
+    #[serde(alias = "low_x18", deserialize_with = "deserialize_u128_str")]
+    pub low: u128,
+    #[serde(alias = "close_x18", deserialize_with = "deserialize_u128_str")]
+    pub close: u128,
+    #[serde(deserialize_with = "deserialize_u128_str")]
+    pub volume: u128,
+}
+
+/// A subaccount being liquidated.  Payloads vary by product type: `liquidator` is only sent by some gateways,
+/// and `is_encoded_spread` only for spread liquidations (a spot product and its perp liquidated together,
+/// both ids encoded in `product_id`), so both are optional and default to None / false.
+#[derive(Debug, Clone, Deserialize)]
+#[allow(dead_code)]
+pub struct LiquidationResponse {
+    pub timestamp: String,
+    pub product_id: ProductId,
+    pub liquidatee: Subaccount,
+    #[serde(default)]
+    pub liquidator: Option<Subaccount>,
+    #[serde(deserialize_with = "deserialize_i128_str")]
+    pub amount: i128, // signed size liquidated, negative when a short position is closed out
+    #[serde(default)]
+    pub is_encoded_spread: bool,
+}
+
+/// Gateway ack for a `place_order` execute
+#[derive(Debug, Deserialize)]
+#[allow(dead_code)]
+pub struct PlaceOrderResponse {
+    pub status: String,
+    pub data: PlaceOrderData,
+    pub request_type: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PlaceOrderData {
+    pub digest: String, // identifies the order, e.g. to cancel it
+}
+
 #[derive(Debug, Deserialize)]
 #[allow(dead_code)]
 pub struct MarketLiquidityResponse {
@@ -53,6 +380,21 @@ pub struct MarketLiquidityData {
     pub timestamp: String,
 }
 
+impl MarketLiquidityResponse {
+    /// Build a snapshot from in-memory levels, e.g. to seed or test a book without the network
+    pub fn new(timestamp: u128, bids: Vec<(u128, u128)>, asks: Vec<(u128, u128)>) -> Self {
+        MarketLiquidityResponse {
+            status: "success".to_string(),
+            data: MarketLiquidityData {
+                bids,
+                asks,
+                timestamp: timestamp.to_string(),
+            },
+            request_type: "query_market_liquidity".to_string(),
+        }
+    }
+}
+
 fn deserialize_bid_ask<'de, D>(deserializer: D) -> Result<Vec<(u128, u128)>, D::Error>
 where
     D: Deserializer<'de>,
@@ -63,28 +405,452 @@ where
     // Convert each string tuple into a tuple of u128
     vec.into_iter()
         .map(|(price, quantity)| {
-            let price = price.parse::<u128>().map_err(de::Error::custom)?;
-            let quantity = quantity.parse::<u128>().map_err(de::Error::custom)?;
+            let price = parse_u128_str(&price).map_err(de::Error::custom)?;
+            let quantity = parse_u128_str(&quantity).map_err(de::Error::custom)?;
             Ok((price, quantity))
         })
         .collect()
 }
 
-#[derive(Debug)]
+fn deserialize_u128_str<'de, D>(deserializer: D) -> Result<u128, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    // Parse as a string, then convert to u128
+    let value: String = Deserialize::deserialize(deserializer)?;
+    parse_u128_str(&value).map_err(de::Error::custom)
+}
+
+// Raw fixed-point integers arrive as plain digits.  Decimal and scientific notation ("43210.0", "4.321e4") are
+// rejected rather than guessed at: such a value may be a whole number of raw units or a human-scale one that still
+// needs the field's decimals (1e18 for x18 fields), and reading it the wrong way passes validation off by that much.
+// Errors name the offending value.
+fn parse_u128_str(value: &str) -> Result<u128, String> {
+    value.parse::<u128>().map_err(|_| {
+        let reason = if value.contains(['.', 'e', 'E']) {
+            "decimal and scientific notation are ambiguous, expected raw fixed-point digits"
+        } else if !value.is_empty() && value.bytes().all(|byte| byte.is_ascii_digit()) {
+            "out of range"
+        } else {
+            "expected an unsigned number"
+        };
+        format!("invalid fixed-point value {:?}: {}", value, reason)
+    })
+}
+
+// Signed counterpart of `parse_u128_str`
+fn parse_i128_str(value: &str) -> Result<i128, String> {
+    if let Ok(parsed) = value.parse::<i128>() {
+        return Ok(parsed);
+    }
+    let (negative, magnitude) = match value.strip_prefix('-') {
+        Some(magnitude) => (true, magnitude),
+        None => (false, value.strip_prefix('+').unwrap_or(value)),
+    };
+    let magnitude = parse_u128_str(magnitude).map_err(|_| format!("invalid fixed-point value {:?}", value))?;
+    let out_of_range = || format!("invalid fixed-point value {:?}: out of range", value);
+    if negative {
+        0i128.checked_sub_unsigned(magnitude).ok_or_else(out_of_range)
+    } else {
+        i128::try_from(magnitude).map_err(|_| out_of_range())
+    }
+}
+
+fn parse_timestamp(timestamp: &str) -> Result<u128, OrderBookError> {
+    timestamp.parse().map_err(|_| OrderBookError::InvalidTimestamp {
+        timestamp: timestamp.to_string(),
+    })
+}
+
+// Reject a book_depth event that couldn't have come from a consistent book: the same price twice on a side
+// (whichever came last would silently win), or new bid levels at or above new ask levels.
+// Deletions (quantity 0) are exempt from the crossing check; they don't rest on the book.
+fn validate_update(book_depth: &BookDepthResponse) -> Result<(), OrderBookError> {
+    for (side, levels) in [(Side::Bid, &book_depth.bids), (Side::Ask, &book_depth.asks)] {
+        let mut seen = HashSet::with_capacity(levels.len());
+        for (price, _) in levels {
+            if !seen.insert(*price) {
+                return Err(OrderBookError::DuplicatePrice { side, price: *price });
+            }
+        }
+    }
+
+    let highest_bid = book_depth.bids.iter().filter(|(_, qty)| *qty > 0).map(|(price, _)| *price).max();
+    let lowest_ask = book_depth.asks.iter().filter(|(_, qty)| *qty > 0).map(|(price, _)| *price).min();
+    if let (Some(bid), Some(ask)) = (highest_bid, lowest_ask) {
+        if bid >= ask {
+            return Err(OrderBookError::CrossedUpdate { bid, ask });
+        }
+    }
+    Ok(())
+}
+
+/// Signed fields (positions, pnl, funding, ...) arrive as decimal strings that may start with `-`.
+/// Like unsigned fields, they must be raw fixed-point digits: decimal and scientific notation are rejected.
+/// Public so downstream models can use it via `#[serde(deserialize_with = "...")]`.
+pub fn deserialize_i128_str<'de, D>(deserializer: D) -> Result<i128, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value: String = Deserialize::deserialize(deserializer)?;
+    parse_i128_str(&value).map_err(de::Error::custom)
+}
+
+/// Signed counterpart of `deserialize_bid_ask` for lists of string pairs
+pub fn deserialize_i128_pairs<'de, D>(deserializer: D) -> Result<Vec<(i128, i128)>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let vec: Vec<(String, String)> = Deserialize::deserialize(deserializer)?;
+
+    vec.into_iter()
+        .map(|(a, b)| {
+            let a = parse_i128_str(&a).map_err(de::Error::custom)?;
+            let b = parse_i128_str(&b).map_err(de::Error::custom)?;
+            Ok((a, b))
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OrderBookError {
+    CrossedBook { highest_bid: u128, lowest_ask: u128 },
+    ZeroQuantity { price: u128 },
+    InvalidPrice { price: u128 },
+    InvalidTimestamp { timestamp: String },
+    DuplicatePrice { side: Side, price: u128 }, // a single update listed the same price twice
+    CrossedUpdate { bid: u128, ask: u128 },     // a single update's own bids and asks cross
+    StaleSnapshot { snapshot_ts: u128, book_ts: u128 }, // the snapshot predates updates already applied
+    OffTick { price: u128, tick_size: u128 },           // a level's price isn't a multiple of the book's tick size
+    InvalidDecimals { decimals: u32 },                  // more fixed-point decimals than a u128 can scale by
+}
+
+impl fmt::Display for OrderBookError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OrderBookError::CrossedBook { highest_bid, lowest_ask } => write!(
+                f,
+                "Bid-Ask Spread Violation: Highest bid ({}) >= Lowest ask ({})",
+                highest_bid, lowest_ask
+            ),
+            OrderBookError::ZeroQuantity { price } => {
+                write!(f, "Quantity Zero Violation: Price {} has zero quantity", price)
+            }
+            OrderBookError::InvalidPrice { price } => {
+                write!(f, "Invalid Price: {} must be > 0 and < infinity (u128::MAX)", price)
+            }
+            OrderBookError::InvalidTimestamp { timestamp } => {
+                write!(f, "Invalid Timestamp: {:?} is not a u128", timestamp)
+            }
+            OrderBookError::DuplicatePrice { side, price } => {
+                write!(f, "Duplicate Price: {:?} level {} appears more than once in one update", side, price)
+            }
+            OrderBookError::CrossedUpdate { bid, ask } => {
+                write!(f, "Crossed Update: bid {} >= ask {} within one update", bid, ask)
+            }
+            OrderBookError::StaleSnapshot { snapshot_ts, book_ts } => {
+                write!(f, "Stale Snapshot: taken at {}, but the book is already at {}", snapshot_ts, book_ts)
+            }
+            OrderBookError::OffTick { price, tick_size } => {
+                write!(f, "Off-Tick Price: {} is not a multiple of the tick size {}", price, tick_size)
+            }
+            OrderBookError::InvalidDecimals { decimals } => {
+                write!(f, "Invalid Decimals: {} is more than {}, the most a u128 can scale by", decimals, MAX_DECIMALS)
+            }
+        }
+    }
+}
+
+impl std::error::Error for OrderBookError {}
+
+impl OrderBookError {
+    /// The event itself was malformed, so `update` rejected it without touching the book.
+    /// The book is still consistent but misses the event's changes, so it needs a resnapshot.
+    pub fn is_malformed_update(&self) -> bool {
+        matches!(self, OrderBookError::DuplicatePrice { .. } | OrderBookError::CrossedUpdate { .. })
+    }
+}
+
+/// Outcome of `OrderBook::update`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateResult {
+    Applied,
+    Stale,                                    // event predates the book's state, dropped
+    // event's last_max_timestamp doesn't match the last applied max_timestamp, or, for the first event after a snapshot,
+    // is newer than the snapshot
+    GapDetected { expected: u128, got: u128 },
+}
+
+/// How the caller should react to an `OrderBookError`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValidationMode {
+    Strict,  // drop the book and resnapshot
+    Lenient, // log and keep applying updates
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Bid,
+    Ask,
+}
+
+/// The best levels of each side as (price, quantity), see `OrderBook::top_levels`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BookLevels {
+    pub bids: Vec<(u128, u128)>, // highest bid first
+    pub asks: Vec<(u128, u128)>, // lowest ask first
+}
+
+/// A price level changed by an update.  Quantities are 0 when the level didn't exist before / was deleted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LevelChange {
+    pub side: Side,
+    pub price: u128,
+    pub old_qty: u128,
+    pub new_qty: u128,
+}
+
+fn push_change(changes: &mut Vec<LevelChange>, side: Side, price: u128, old_qty: Option<u128>, new_qty: u128) {
+    let old_qty = old_qty.unwrap_or(0);
+    if old_qty != new_qty {
+        changes.push(LevelChange { side, price, old_qty, new_qty });
+    }
+}
+
+/// A level where the local book and a snapshot disagree, see `OrderBook::verify_against_snapshot`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LevelMismatch {
+    pub side: Side,
+    pub price: u128,
+    pub local: Option<u128>,    // quantity in the local book, None if the level is missing
+    pub snapshot: Option<u128>, // quantity in the snapshot, None if the level is missing
+}
+
+// One side's levels in price priority, see `OrderBook::levels`.  An enum rather than a boxed iterator, so walking
+// the book doesn't allocate.
+enum Levels<'a> {
+    Bids(std::iter::Rev<btree_map::Iter<'a, u128, u128>>), // highest bid first
+    Asks(btree_map::Iter<'a, u128, u128>),                 // lowest ask first
+}
+
+impl<'a> Iterator for Levels<'a> {
+    type Item = (&'a u128, &'a u128);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Levels::Bids(levels) => levels.next(),
+            Levels::Asks(levels) => levels.next(),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match self {
+            Levels::Bids(levels) => levels.size_hint(),
+            Levels::Asks(levels) => levels.size_hint(),
+        }
+    }
+}
+
+// a * b / denominator without overflowing the intermediate product.  None if the result doesn't fit in u128.
+fn mul_div(a: u128, b: u128, denominator: u128) -> Option<u128> {
+    let result = U256::from(a) * U256::from(b) / U256::from(denominator);
+    u128::try_from(result).ok()
+}
+
+// Saturates at U256::MAX: one level's price * quantity always fits, but summing several near u128::MAX doesn't
+fn notional(levels: &BTreeMap<u128, u128>) -> U256 {
+    levels
+        .iter()
+        .fold(U256::zero(), |total, (price, quantity)| total.saturating_add(U256::from(*price) * U256::from(*quantity)))
+}
+
+/// Decimal places used when rendering the book, see `OrderBook::set_display_precision`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DisplayPrecision {
+    pub price: usize,
+    pub size: usize, // quantities and cumulative quantities
+}
+
+impl Default for DisplayPrecision {
+    fn default() -> Self {
+        DisplayPrecision { price: 2, size: 10 }
+    }
+}
+
+pub const CLEAR_SCREEN: &str = "\x1B[2J\x1B[H"; // clear screen and reset cursor to top-left
+
+// ANSI styles for `OrderBook::set_color`
+const ASK_COLOR: &str = "\x1B[31m"; // red
+const BEST_ASK_COLOR: &str = "\x1B[1;31m"; // bold red
+const BID_COLOR: &str = "\x1B[32m"; // green
+const BEST_BID_COLOR: &str = "\x1B[1;32m"; // bold green
+const RESET_COLOR: &str = "\x1B[0m";
+
+// Wrap an already padded cell in `style`, so the escape codes don't count towards its width
+fn paint(cell: String, style: &str) -> String {
+    format!("{}{}{}", style, cell, RESET_COLOR)
+}
+
+const MAX_DECIMALS: u32 = 38; // 10^38 is the largest power of ten a u128 holds
+
+fn check_decimals(decimals: u32) -> Result<u32, OrderBookError> {
+    match decimals {
+        0..=MAX_DECIMALS => Ok(decimals),
+        _ => Err(OrderBookError::InvalidDecimals { decimals }),
+    }
+}
+
+// Decimals of a deserialized book, held to the same limit as `OrderBook::new`
+fn deserialize_decimals<'de, D>(deserializer: D) -> Result<u32, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    check_decimals(u32::deserialize(deserializer)?).map_err(de::Error::custom)
+}
+
+// Levels serialize as ordered [price, quantity] string pairs, the same shape Vertex sends them in
+#[serde_as]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct OrderBook {
+    #[serde_as(as = "Vec<(DisplayFromStr, DisplayFromStr)>")]
     bids: BTreeMap<u128, u128>, // Price -> Quantity
+    #[serde_as(as = "Vec<(DisplayFromStr, DisplayFromStr)>")]
     asks: BTreeMap<u128, u128>,
+    #[serde(deserialize_with = "deserialize_decimals")]
+    price_decimals: u32, // fixed-point decimals used by the product's prices
+    #[serde(deserialize_with = "deserialize_decimals")]
+    size_decimals: u32,  // fixed-point decimals used by the product's quantities
+    #[serde_as(as = "DisplayFromStr")]
+    last_update_ts: u128, // timestamp of the last applied snapshot/update, 0 if none yet
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    last_max_timestamp: Option<u128>, // max_timestamp of the last book_depth update applied since the last snapshot
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    tick_size: Option<u128>, // minimum price increment in fixed-point units; every level's price must be a multiple
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    product_id: Option<ProductId>, // the product this is the book of, if known
+    #[serde(skip)]
+    symbol: Option<String>, // presentation only: the product's name in `summary` and `visualize*`
+    #[serde(skip)]
+    display_precision: DisplayPrecision, // presentation only, not part of the book's state
+    #[serde(skip)]
+    color: bool, // presentation only: ANSI colors in `visualize*`
+    #[serde(skip)]
+    fast_validation: bool, // only the constant-time checks in `validate_orderbook`, see `set_full_validation`
 }
 
 impl OrderBook {
-    pub fn new() -> Self {
-        OrderBook {
+    /// `price_decimals` / `size_decimals` are the fixed-point exponents used to scale raw values
+    /// into dollars / units.  Passing 18 for both preserves the original 1e18 behavior.
+    /// More than 38 (10^38 is the largest power of ten a u128 holds) is rejected with `InvalidDecimals`.
+    pub fn new(price_decimals: u32, size_decimals: u32) -> Result<Self, OrderBookError> {
+        check_decimals(price_decimals)?;
+        check_decimals(size_decimals)?;
+        Ok(OrderBook {
             bids: BTreeMap::new(),
             asks: BTreeMap::new(),
-        }
+            price_decimals,
+            size_decimals,
+            last_update_ts: 0,
+            last_max_timestamp: None,
+            tick_size: None,
+            product_id: None,
+            symbol: None,
+            display_precision: DisplayPrecision::default(),
+            color: false,
+            fast_validation: false,
+        })
+    }
+
+    /// Whether every snapshot and update is followed by the per-level checks (no zero quantities, prices on the tick),
+    /// which scan the whole book.  On by default.  Turning them off leaves only the constant-time top-of-book checks
+    /// (not crossed, prices in range), which saves latency on deep books, but a book corrupted some other way then
+    /// goes unnoticed until it is reconciled against a snapshot (see `verify_against_snapshot`).
+    pub fn set_full_validation(&mut self, enabled: bool) {
+        self.fast_validation = !enabled;
+    }
+
+    /// The product's minimum price increment, in fixed-point price units.  When set, validation rejects any level
+    /// whose price isn't a multiple of it, which catches prices scaled with the wrong decimals.
+    /// None (or 0) disables the check.
+    pub fn set_tick_size(&mut self, tick_size: Option<u128>) {
+        self.tick_size = tick_size.filter(|tick_size| *tick_size > 0);
+    }
+
+    pub fn tick_size(&self) -> Option<u128> {
+        self.tick_size
+    }
+
+    /// The product this is the book of, shown by `summary`.  `OrderBookBuilder` and `MultiBook` set it.
+    pub fn set_product_id(&mut self, product_id: Option<ProductId>) {
+        self.product_id = product_id;
+    }
+
+    pub fn product_id(&self) -> Option<ProductId> {
+        self.product_id
+    }
+
+    /// The product's symbol, e.g. "BTC-USDC-PERP" (see `SymbolRegistry`), shown by `summary` and `visualize*`
+    pub fn set_symbol(&mut self, symbol: Option<String>) {
+        self.symbol = symbol;
     }
 
-    pub fn from_snapshot(&mut self, snapshot: MarketLiquidityResponse) {
+    pub fn symbol(&self) -> Option<&str> {
+        self.symbol.as_deref()
+    }
+
+    /// Decimal places `visualize*` and `summary` print prices and quantities with, e.g. 2 and 4 for BTC
+    pub fn set_display_precision(&mut self, precision: DisplayPrecision) {
+        self.display_precision = precision;
+    }
+
+    pub fn display_precision(&self) -> DisplayPrecision {
+        self.display_precision
+    }
+
+    /// Color `visualize*` output with ANSI escapes: asks red, bids green, the best bid/ask in bold.
+    /// Off by default; only turn it on when writing to a terminal, or piped output fills with escape codes.
+    pub fn set_color(&mut self, color: bool) {
+        self.color = color;
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("order book serializes to json")
+    }
+
+    pub fn from_json(s: &str) -> Result<OrderBook, serde_json::Error> {
+        serde_json::from_str(s)
+    }
+
+    /// Timestamp of the last applied snapshot/update, 0 if none yet
+    pub fn last_update_ts(&self) -> u128 {
+        self.last_update_ts
+    }
+
+    /// max_timestamp of the last book_depth update applied since the last snapshot.
+    /// None if no update has been applied on top of the current snapshot yet.
+    pub fn last_update_timestamp(&self) -> Option<u128> {
+        self.last_max_timestamp
+    }
+
+    fn price_scale(&self) -> u128 {
+        10u128.pow(self.price_decimals)
+    }
+
+    fn size_scale(&self) -> u128 {
+        10u128.pow(self.size_decimals)
+    }
+
+    /// Replace the book with `snapshot`.  A snapshot older than what the book already reflects is rejected
+    /// with `StaleSnapshot`, leaving the book as it was: applying it would move the book backwards.
+    pub fn from_snapshot(&mut self, snapshot: MarketLiquidityResponse) -> Result<(), OrderBookError> {
+        let snapshot_ts = parse_timestamp(&snapshot.data.timestamp)?;
+        if snapshot_ts < self.last_update_ts {
+            return Err(OrderBookError::StaleSnapshot { snapshot_ts, book_ts: self.last_update_ts });
+        }
+        self.last_update_ts = snapshot_ts;
+        self.last_max_timestamp = None;
         self.bids.clear();
         self.asks.clear();
 
@@ -104,135 +870,588 @@ impl OrderBook {
             }
         }
 
-        self.validate_orderbook();
+        self.validate_orderbook()
     }
 
-    pub fn update(&mut self, book_depth: BookDepthResponse) {
+    /// Replace the book with levels saved earlier (price -> quantity), e.g. to rehydrate it after a restart and
+    /// resume applying updates without waiting for a snapshot.  `last_ts` is the timestamp the levels reflect:
+    /// updates it covers are dropped as stale, and the first newer one is applied like the first after a snapshot.
+    /// The seed gets every check, whatever `set_full_validation` says; an inconsistent one is rejected, leaving
+    /// the book as it was.  Decimals, tick size and display settings are kept.
+    pub fn from_levels(
+        &mut self,
+        bids: BTreeMap<u128, u128>,
+        asks: BTreeMap<u128, u128>,
+        last_ts: u128,
+    ) -> Result<(), OrderBookError> {
+        let old_bids = mem::replace(&mut self.bids, bids);
+        let old_asks = mem::replace(&mut self.asks, asks);
+        let fast_validation = mem::replace(&mut self.fast_validation, false);
+        let validation = self.validate_orderbook();
+        self.fast_validation = fast_validation;
+        if let Err(e) = validation {
+            self.bids = old_bids;
+            self.asks = old_asks;
+            return Err(e);
+        }
+
+        self.last_update_ts = last_ts;
+        self.last_max_timestamp = None;
+        Ok(())
+    }
+
+    /// Apply a book_depth event.  Events already covered by the book are skipped as `Stale`, and an event
+    /// that doesn't chain onto the previously applied one is reported as `GapDetected` without being applied.
+    /// The first event after a snapshot has to pick up at or before the snapshot's timestamp.
+    /// Malformed events (duplicate prices, crossed levels within the event) are rejected before anything is applied.
+    pub fn update(&mut self, book_depth: BookDepthResponse) -> Result<UpdateResult, OrderBookError> {
+        self.update_with_changes(book_depth).map(|(result, _)| result)
+    }
+
+    /// Same as `update`, but also returns the levels the event actually changed (empty unless `Applied`).
+    /// Levels whose quantity didn't change are left out.
+    pub fn update_with_changes(
+        &mut self,
+        book_depth: BookDepthResponse,
+    ) -> Result<(UpdateResult, Vec<LevelChange>), OrderBookError> {
+        let last_max_timestamp = book_depth.last_max_timestamp;
+        let max_timestamp = book_depth.max_timestamp;
+
+        if max_timestamp <= self.last_update_ts {
+            return Ok((UpdateResult::Stale, Vec::new())); // nothing newer than the snapshot/last update
+        }
+
+        match self.last_max_timestamp {
+            Some(expected) if last_max_timestamp != expected => {
+                return Ok((UpdateResult::GapDetected { expected, got: last_max_timestamp }, Vec::new()));
+            }
+            // first event since the snapshot: anything between the snapshot and this event's predecessor was missed
+            None if last_max_timestamp > self.last_update_ts => {
+                let expected = self.last_update_ts;
+                return Ok((UpdateResult::GapDetected { expected, got: last_max_timestamp }, Vec::new()));
+            }
+            _ => {}
+        }
+
+        validate_update(&book_depth)?;
+
+        self.last_update_ts = max_timestamp;
+        self.last_max_timestamp = Some(max_timestamp);
+
+        let mut changes = Vec::with_capacity(book_depth.bids.len() + book_depth.asks.len());
+
         // Update bids
         for (price, quantity) in book_depth.bids {
-            if quantity == 0 {
-                self.bids.remove(&price);
+            let old_qty = if quantity == 0 {
+                self.bids.remove(&price)
             } else {
-                self.bids.insert(price, quantity);
-            }
+                self.bids.insert(price, quantity)
+            };
+            push_change(&mut changes, Side::Bid, price, old_qty, quantity);
         }
 
         // Update asks
         for (price, quantity) in book_depth.asks {
-            if quantity == 0 {
-                self.asks.remove(&price);
+            let old_qty = if quantity == 0 {
+                self.asks.remove(&price)
             } else {
-                self.asks.insert(price, quantity);
+                self.asks.insert(price, quantity)
+            };
+            push_change(&mut changes, Side::Ask, price, old_qty, quantity);
+        }
+
+        self.validate_orderbook()?;
+        Ok((UpdateResult::Applied, changes))
+    }
+
+    /// Highest bid as (price, quantity).  None if there are no bids.
+    pub fn best_bid(&self) -> Option<(u128, u128)> {
+        self.bids.iter().next_back().map(|(price, quantity)| (*price, *quantity))
+    }
+
+    /// Lowest ask as (price, quantity).  None if there are no asks.
+    pub fn best_ask(&self) -> Option<(u128, u128)> {
+        self.asks.iter().next().map(|(price, quantity)| (*price, *quantity))
+    }
+
+    /// Lowest ask - highest bid, in fixed-point units.  None if either side is empty (or the book is crossed).
+    pub fn spread(&self) -> Option<u128> {
+        match (self.best_bid(), self.best_ask()) {
+            (Some((bid_price, _)), Some((ask_price, _))) => ask_price.checked_sub(bid_price),
+            _ => None,
+        }
+    }
+
+    /// (best bid + best ask) / 2 in fixed-point units, rounded down when the sum is odd.
+    /// None if either side is empty.
+    pub fn mid_raw(&self) -> Option<u128> {
+        match (self.best_bid(), self.best_ask()) {
+            // halve before adding so two prices near u128::MAX can't overflow
+            (Some((bid_price, _)), Some((ask_price, _))) => {
+                Some(bid_price / 2 + ask_price / 2 + (bid_price % 2 + ask_price % 2) / 2)
             }
+            _ => None,
         }
+    }
 
-        self.validate_orderbook();
+    /// Mid price scaled down by the price decimals into dollars.  None if either side is empty.
+    pub fn mid(&self) -> Option<f64> {
+        match (self.best_bid(), self.best_ask()) {
+            (Some((bid_price, _)), Some((ask_price, _))) => {
+                Some((bid_price as f64 + ask_price as f64) / 2.0 / self.price_scale() as f64)
+            }
+            _ => None,
+        }
     }
 
-    fn validate_orderbook(&mut self) {
-        // Check that all bids are less than asks
-        if let (Some(highest_bid), Some(lowest_ask)) = (self.bids.iter().next_back(), self.asks.iter().next()) {
-            assert!(
-                highest_bid.0 < lowest_ask.0,
-                "Bid-Ask Spread Violation: Highest bid ({}) >= Lowest ask ({})",
-                highest_bid.0,
-                lowest_ask.0
-            );
+    /// Size-weighted mid, in dollars: (bid_price * ask_size + ask_price * bid_size) / (bid_size + ask_size),
+    /// which leans towards the side more likely to trade through.  None if either side is empty or both
+    /// top-of-book sizes are 0.
+    pub fn microprice(&self) -> Option<f64> {
+        let ((bid_price, bid_size), (ask_price, ask_size)) = (self.best_bid()?, self.best_ask()?);
+        let total_size = U256::from(bid_size) + U256::from(ask_size);
+        if total_size.is_zero() {
+            return None;
         }
+        // exact in U256 (price * size overflows u128), then whole and fractional dollars converted separately, as in `vwap`
+        let weighted = (U256::from(bid_price) * U256::from(ask_size)).checked_add(U256::from(ask_price) * U256::from(bid_size))?;
+        let microprice = u128::try_from(weighted / total_size).ok()?; // a weighted average of two u128 prices fits
+        let price_scale = self.price_scale();
+        Some((microprice / price_scale) as f64 + (microprice % price_scale) as f64 / price_scale as f64)
+    }
 
-        // Check that all quantities are > 0
-        for (price, quantity) in self.bids.iter().chain(self.asks.iter()) {
-            assert!(
-                *quantity > 0,
-                "Quantity Zero Violation: Price {} has zero quantity",
-                price
-            );
+    /// Same as `spread()` but scaled down by the price decimals into dollars.
+    pub fn spread_scaled(&self) -> Option<f64> {
+        self.spread().map(|spread| spread as f64 / self.price_scale() as f64)
+    }
+
+    /// The best `n` levels of each side (fewer if the side is shallower), in fixed-point units
+    pub fn top_levels(&self, n: usize) -> BookLevels {
+        let levels = |side: Side| self.levels(side).take(n).map(|(price, quantity)| (*price, *quantity)).collect();
+        BookLevels {
+            bids: levels(Side::Bid),
+            asks: levels(Side::Ask),
+        }
+    }
+
+    pub fn num_bid_levels(&self) -> usize {
+        self.bids.len()
+    }
+
+    pub fn num_ask_levels(&self) -> usize {
+        self.asks.len()
+    }
+
+    /// True once the book has both sides from a snapshot and at least one in-sequence update applied on top of it,
+    /// i.e. it is safe to act on.  Goes false again while a resnapshot (after a gap or reconnect) is being confirmed.
+    pub fn is_ready(&self) -> bool {
+        !self.bids.is_empty() && !self.asks.is_empty() && self.last_max_timestamp.is_some()
+    }
+
+    /// True if neither side has any levels.  A one-sided book isn't empty; check the level counts for that.
+    pub fn is_empty(&self) -> bool {
+        self.bids.is_empty() && self.asks.is_empty()
+    }
+
+    /// Sum of all resting bid quantities, in fixed-point size units
+    pub fn total_bid_volume(&self) -> u128 {
+        self.bids.values().fold(0, |total, quantity| total.saturating_add(*quantity))
+    }
+
+    /// Sum of all resting ask quantities, in fixed-point size units
+    pub fn total_ask_volume(&self) -> u128 {
+        self.asks.values().fold(0, |total, quantity| total.saturating_add(*quantity))
+    }
+
+    /// Sum of price * quantity over all bids.  Carries both scales (price_decimals + size_decimals),
+    /// which overflows u128 for a realistic book, hence U256.  Saturates at `U256::MAX` rather than overflowing.
+    pub fn total_bid_notional(&self) -> U256 {
+        notional(&self.bids)
+    }
+
+    /// Sum of price * quantity over all asks, see `total_bid_notional`
+    pub fn total_ask_notional(&self) -> U256 {
+        notional(&self.asks)
+    }
+
+    /// (price, quantity) levels of `side` in price priority, from the top of book outwards:
+    /// bids high to low, asks low to high
+    pub fn levels(&self, side: Side) -> impl Iterator<Item = (&u128, &u128)> + '_ {
+        match side {
+            Side::Bid => Levels::Bids(self.bids.iter().rev()),
+            Side::Ask => Levels::Asks(self.asks.iter()),
+        }
+    }
+
+    /// Volume-weighted average price (in dollars) of the top `levels` price levels of `side`.
+    /// None if the side is empty or `levels` is 0.
+    pub fn vwap(&self, side: Side, levels: usize) -> Option<f64> {
+        let price_scale = self.price_scale();
+        // whole and fractional dollars separately, so the conversion doesn't add rounding error of its own
+        self.vwap_raw(side, levels)
+            .map(|vwap| (vwap / price_scale) as f64 + (vwap % price_scale) as f64 / price_scale as f64)
+    }
+
+    /// `vwap` in fixed-point price units, rounded down.  Accumulated exactly in U256: a deep book's
+    /// price * quantity sum overflows u128, and summing in f64 loses precision level by level.
+    /// None if even U256 overflows, which takes several levels with price * quantity near `u128::MAX`².
+    pub fn vwap_raw(&self, side: Side, levels: usize) -> Option<u128> {
+        let (notional, volume) = self
+            .levels(side)
+            .take(levels)
+            .try_fold((U256::zero(), U256::zero()), |(notional, volume), (price, quantity)| {
+                let notional = notional.checked_add(U256::from(*price) * U256::from(*quantity))?;
+                Some((notional, volume + U256::from(*quantity))) // a sum of u128s can't overflow U256
+            })?;
+
+        if volume.is_zero() {
+            return None;
+        }
+        u128::try_from(notional / volume).ok() // a weighted average of u128 prices always fits
+    }
+
+    /// Average of the bid and ask `vwap` over the top `levels` levels.  None if either side is empty.
+    pub fn mid_vwap(&self, levels: usize) -> Option<f64> {
+        match (self.vwap(Side::Bid, levels), self.vwap(Side::Ask, levels)) {
+            (Some(bid_vwap), Some(ask_vwap)) => Some((bid_vwap + ask_vwap) / 2.0),
+            _ => None,
+        }
+    }
+
+    /// (bid_volume - ask_volume) / (bid_volume + ask_volume) over the top `levels` levels of each side, in [-1, 1].
+    /// A side with fewer than `levels` levels contributes what it has.  None if both sides are empty.
+    pub fn imbalance(&self, levels: usize) -> Option<f64> {
+        let bid_volume = self.levels(Side::Bid).take(levels).map(|(_, quantity)| *quantity as f64).sum::<f64>();
+        let ask_volume = self.levels(Side::Ask).take(levels).map(|(_, quantity)| *quantity as f64).sum::<f64>();
+
+        let total = bid_volume + ask_volume;
+        if total > 0.0 {
+            Some((bid_volume - ask_volume) / total)
+        } else {
+            None
+        }
+    }
+
+    /// Cumulative `side` quantity within each of `band_bps` basis points of the mid price, as (band, quantity)
+    /// in the order given.  E.g. `&[10, 25]` totals the levels within 0.10% and within 0.25% of the mid.
+    /// Empty if the book is one-sided (no mid).  Totals saturate at `u128::MAX`, like `total_bid_volume`.
+    pub fn liquidity_bands(&self, side: Side, band_bps: &[u32]) -> Vec<(u32, u128)> {
+        let mid = match self.mid_raw() {
+            Some(mid) => mid,
+            None => return Vec::new(),
+        };
+        let widest = band_bps.iter().copied().max().unwrap_or(0);
+        let mut bands: Vec<(u32, u128)> = band_bps.iter().map(|bps| (*bps, 0)).collect();
+
+        for (price, quantity) in self.levels(side) {
+            // distance / mid <= bps / 10_000, compared without dividing
+            let distance = price.abs_diff(mid).saturating_mul(10_000);
+            if distance > mid.saturating_mul(widest as u128) {
+                break; // levels only get further from the mid
+            }
+            for (bps, total) in bands.iter_mut() {
+                if distance <= mid.saturating_mul(*bps as u128) {
+                    *total = total.saturating_add(*quantity);
+                }
+            }
+        }
+
+        bands
+    }
+
+    /// Walks `side` from the top of book to fill `size` units: `Side::Ask` prices a buy (lifting asks),
+    /// `Side::Bid` prices a sell (hitting bids).  Returns (total_cost, average_price) in fixed-point units,
+    /// or None if `size` is 0 or the side doesn't have enough liquidity to fill it.
+    pub fn depth_for_size(&self, side: Side, size: u128) -> Option<(u128, u128)> {
+        if size == 0 {
+            return None;
+        }
+
+        let size_scale = self.size_scale();
+        let mut remaining = size;
+        let mut total_cost: u128 = 0;
+        for (price, quantity) in self.levels(side) {
+            let fill = remaining.min(*quantity);
+            total_cost = total_cost.checked_add(mul_div(*price, fill, size_scale)?)?;
+            remaining -= fill;
+            if remaining == 0 {
+                return Some((total_cost, mul_div(total_cost, size_scale, size)?));
+            }
+        }
+
+        None // not enough liquidity
+    }
+
+    /// Total resting quantity a limit order at `limit_price` could take: like `depth_for_size`, `Side::Ask` is a buy
+    /// (asks at or below `limit_price`) and `Side::Bid` a sell (bids at or above it).  0 if no level qualifies.
+    pub fn volume_through_price(&self, side: Side, limit_price: u128) -> u128 {
+        let levels = match side {
+            Side::Bid => self.bids.range(limit_price..),
+            Side::Ask => self.asks.range(..=limit_price),
+        };
+        levels.fold(0u128, |total, (_, quantity)| total.saturating_add(*quantity))
+    }
+
+    /// Every level that differs between this book and `other`, as the change that turns this one into `other`:
+    /// `old_qty` is this book's quantity and `new_qty` is `other`'s, 0 where a side lacks the level.
+    /// Bids then asks, each in ascending price order.  Neither book is modified.
+    pub fn diff(&self, other: &OrderBook) -> Vec<LevelChange> {
+        let mut changes = Vec::new();
+        for (side, ours, theirs) in [(Side::Bid, &self.bids, &other.bids), (Side::Ask, &self.asks, &other.asks)] {
+            let prices: BTreeSet<&u128> = ours.keys().chain(theirs.keys()).collect();
+            for price in prices {
+                push_change(&mut changes, side, *price, ours.get(price).copied(), theirs.get(price).copied().unwrap_or(0));
+            }
+        }
+        changes
+    }
+
+    /// Compare the book against a freshly fetched snapshot and return every level that differs (empty if consistent).
+    /// Snapshots are depth-capped, so each side is only compared from the top of book down to the snapshot's
+    /// deepest price; local levels beyond that are not checked.  The snapshot should be taken at (or very near)
+    /// the book's `last_update_ts`, otherwise in-flight updates show up as mismatches.
+    pub fn verify_against_snapshot(&self, snapshot: &MarketLiquidityResponse) -> Vec<LevelMismatch> {
+        let mut mismatches = Vec::new();
+        for (side, levels) in [(Side::Bid, &snapshot.data.bids), (Side::Ask, &snapshot.data.asks)] {
+            let local = match side {
+                Side::Bid => &self.bids,
+                Side::Ask => &self.asks,
+            };
+            let expected: BTreeMap<u128, u128> = levels
+                .iter()
+                .filter(|(_, quantity)| *quantity != 0)
+                .cloned()
+                .collect();
+
+            // the overlap runs from the top of book to the snapshot's deepest level
+            let deepest = match side {
+                Side::Bid => expected.keys().next().copied(),
+                Side::Ask => expected.keys().next_back().copied(),
+            };
+            let in_range = |price: u128| match (side, deepest) {
+                (Side::Bid, Some(deepest)) => price >= deepest,
+                (Side::Ask, Some(deepest)) => price <= deepest,
+                (_, None) => true, // empty snapshot side: the whole local side should be empty too
+            };
+
+            for (&price, &quantity) in local.iter().filter(|(price, _)| in_range(**price)) {
+                if expected.get(&price) != Some(&quantity) {
+                    mismatches.push(LevelMismatch { side, price, local: Some(quantity), snapshot: expected.get(&price).copied() });
+                }
+            }
+            for (&price, &quantity) in expected.iter().filter(|(price, _)| !local.contains_key(price)) {
+                mismatches.push(LevelMismatch { side, price, local: None, snapshot: Some(quantity) });
+            }
+        }
+
+        mismatches
+    }
+
+    fn validate_orderbook(&self) -> Result<(), OrderBookError> {
+        // Check that all bids are less than asks
+        if let (Some(highest_bid), Some(lowest_ask)) = (self.bids.iter().next_back(), self.asks.iter().next()) {
+            if highest_bid.0 >= lowest_ask.0 {
+                return Err(OrderBookError::CrossedBook {
+                    highest_bid: *highest_bid.0,
+                    lowest_ask: *lowest_ask.0,
+                });
+            }
         }
 
         // Check that bids > 0
         if let Some((price, _)) = self.bids.iter().next() {
-            assert!(
-                *price > 0,
-                "Invalid Bid Price: Bid price must be greater than 0"
-            );
+            if *price == 0 {
+                return Err(OrderBookError::InvalidPrice { price: *price });
+            }
         }
 
         // Check that asks < ∞ .  Price bounds might be more appropriate here.
         if let Some((price, _)) = self.asks.iter().next_back() {
-            assert!(
-                *price < u128::MAX,
-                "Invalid Ask Price: Ask price must be less than infinity (u128::MAX)"
-            );
+            if *price == u128::MAX {
+                return Err(OrderBookError::InvalidPrice { price: *price });
+            }
+        }
+
+        // The rest scans every level
+        if self.fast_validation {
+            return Ok(());
         }
+
+        // Check that all quantities are > 0
+        for (price, quantity) in self.bids.iter().chain(self.asks.iter()) {
+            if *quantity == 0 {
+                return Err(OrderBookError::ZeroQuantity { price: *price });
+            }
+        }
+
+        // Check that every price sits on the tick grid
+        if let Some(tick_size) = self.tick_size {
+            for price in self.bids.keys().chain(self.asks.keys()) {
+                if price % tick_size != 0 {
+                    return Err(OrderBookError::OffTick { price: *price, tick_size });
+                }
+            }
+        }
+
+        Ok(())
     }
+
+    /// One-line summary of the top of book, e.g.
+    /// `product=2 symbol=BTC-USDC-PERP bid=43210.50x1.23 ask=43211.00x0.98 spread=0.50 mid=43210.75`
+    /// (`symbol=` only if one is set).  No terminal control codes, so it is safe to log.  Missing values print as `N/A`.
+    pub fn summary(&self) -> String {
+        let precision = self.display_precision;
+        let price_scale = self.price_scale() as f64;
+        let size_scale = self.size_scale() as f64;
+        let level = |level: Option<(u128, u128)>| match level {
+            Some((price, quantity)) => format!(
+                "{:.*}x{:.*}",
+                precision.price,
+                price as f64 / price_scale,
+                precision.size,
+                quantity as f64 / size_scale
+            ),
+            None => "N/A".to_string(),
+        };
+        let spread = match self.spread_scaled() {
+            Some(spread) => format!("{:.*}", precision.price, spread),
+            None => "N/A".to_string(),
+        };
+        let mid = match self.mid() {
+            Some(mid) => format!("{:.*}", precision.price, mid),
+            None => "N/A".to_string(),
+        };
+
+        let product_id = match self.product_id {
+            Some(product_id) => product_id.to_string(),
+            None => "N/A".to_string(),
+        };
+        let symbol = match &self.symbol {
+            Some(symbol) => format!(" symbol={}", symbol),
+            None => String::new(),
+        };
+
+        format!(
+            "product={}{} bid={} ask={} spread={} mid={}",
+            product_id, symbol, level(self.best_bid()), level(self.best_ask()), spread, mid
+        )
+    }
+
+    /// Render the full book as a text table.  Pure text, no terminal control codes.
     pub fn visualize(&self) -> String {
-        let mut output = String::new();
-        output.push_str("\x1B[2J\x1B[H"); // Clear screen and reset cursor to top-left
+        self.visualize_depth(usize::MAX)
+    }
 
-        // Calculate the market price (midpoint)
-        let best_bid = self.bids.iter().next_back(); // Highest bid
-        let best_ask = self.asks.iter().next();     // Lowest ask
-        let market_price = match (best_bid, best_ask) {
-            (Some((bid_price, _)), Some((ask_price, _))) => {
-                let bid_scaled = *bid_price as f64 / 1_000_000_000_000_000_000.0;
-                let ask_scaled = *ask_price as f64 / 1_000_000_000_000_000_000.0;
-                Some((bid_scaled + ask_scaled) / 2.0)
-            }
-            _ => None,
+    /// `visualize()` prefixed with `CLEAR_SCREEN`, for redrawing a dedicated terminal in place.
+    pub fn visualize_clearing(&self) -> String {
+        format!("{}{}", CLEAR_SCREEN, self.visualize())
+    }
+
+    /// One compact JSON object (no newline) with the top `levels` levels per side, for NDJSON output:
+    /// `{"timestamp":"..","best_bid":["price","qty"],"best_ask":[..],"spread":"..","bids":[[..]],"asks":[[..]]}`.
+    /// Fixed-point values are strings, like `to_json`; missing values are null.
+    pub fn visualize_json(&self, levels: usize) -> String {
+        let level = |(price, quantity): (u128, u128)| json!([price.to_string(), quantity.to_string()]);
+        let side = |side: Side| -> Vec<serde_json::Value> {
+            self.levels(side).take(levels).map(|(price, quantity)| level((*price, *quantity))).collect()
         };
 
-        // Display the market price
-        output.push_str("Order Book\n");
+        json!({
+            "timestamp": self.last_update_ts.to_string(),
+            "best_bid": self.best_bid().map(level),
+            "best_ask": self.best_ask().map(level),
+            "spread": self.spread().map(|spread| spread.to_string()),
+            "bids": side(Side::Bid),
+            "asks": side(Side::Ask),
+        })
+            .to_string()
+    }
+
+    /// Like `visualize`, but renders only the top `levels` levels per side.
+    /// Each side also shows its cumulative quantity from the top of the book down to that row.
+    pub fn visualize_depth(&self, levels: usize) -> String {
+        let precision = self.display_precision;
+        let mut output = String::new();
+
+        // Display the product and the market price (midpoint)
+        match (&self.symbol, self.product_id) {
+            (Some(symbol), _) => output.push_str(&format!("Order Book: {}\n", symbol)),
+            (None, Some(product_id)) => output.push_str(&format!("Order Book: product {}\n", product_id)),
+            (None, None) => output.push_str("Order Book\n"),
+        }
         output.push_str("=================\n");
-        match market_price {
-            Some(price) => output.push_str(&format!("Market Price: {:.2}\n\n", price)),
+        match self.mid() {
+            Some(price) => output.push_str(&format!("Market Price: {:.*}\n\n", precision.price, price)),
             None => output.push_str("Market Price: N/A\n\n"),
         }
 
-        // Add headers for asks and bids
-        output.push_str(format!("{:<30} {:>30}\n", "Asks (Price -> Quantity)", "Bids (Price -> Quantity)").as_str());
-        output.push_str(format!("{:=<60}\n", "").as_str()); // Separator
-
-        let mut asks_iter = self.asks.iter();
-        let mut bids_iter = self.bids.iter().rev();
-
-        loop {
-            let ask = asks_iter.next();
-            let bid = bids_iter.next();
+        // (price, quantity, cumulative quantity) per level, formatted up front so the columns fit the widest value
+        let price_scale = self.price_scale() as f64;
+        let size_scale = self.size_scale() as f64;
+        let format_side = |side: &mut dyn Iterator<Item = (&u128, &u128)>| {
+            let mut cumulative: f64 = 0.0;
+            side.take(levels)
+                .map(|(price, quantity)| {
+                    let price = *price as f64 / price_scale; // Convert to dollars
+                    let quantity = *quantity as f64 / size_scale; // Convert to units
+                    cumulative += quantity;
+                    (
+                        format!("{:.*}", precision.price, price),
+                        format!("{:.*}", precision.size, quantity),
+                        format!("{:.*}", precision.size, cumulative),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        let asks = format_side(&mut self.levels(Side::Ask));
+        let bids = format_side(&mut self.levels(Side::Bid));
 
-            match (ask, bid) {
-                (Some((ask_price, ask_quantity)), Some((bid_price, bid_quantity))) => {
-                    let ask_price_scaled = *ask_price / 1_000_000_000_000_000_000; // Convert to dollars
-                    let ask_quantity_scaled = *ask_quantity as f64 / 1e18;         // Convert to units
+        let price_width = asks.iter().chain(&bids).map(|(price, _, _)| price.len()).fold(12, usize::max);
+        let size_width = asks
+            .iter()
+            .chain(&bids)
+            .map(|(_, quantity, cumulative)| quantity.len().max(cumulative.len()))
+            .fold(15, usize::max);
+        let side_width = price_width + size_width * 2 + 7; // " -> " and " | "
 
-                    let bid_price_scaled = *bid_price / 1_000_000_000_000_000_000; // Convert to dollars
-                    let bid_quantity_scaled = *bid_quantity as f64 / 1e18;         // Convert to units
+        // Add headers for asks and bids
+        output.push_str(&format!(
+            "{:<side_width$} {:>side_width$}\n",
+            "Asks (Price -> Quantity | Cumulative)", "Bids (Price -> Quantity | Cumulative)",
+            side_width = side_width
+        ));
+        output.push_str(&format!("{:=<width$}\n", "", width = side_width * 2 + 1)); // Separator
 
-                    output.push_str(&format!(
-                        "{:<15.2} -> {:<15.10} {:>15.2} -> {:>15.10}\n",
-                        ask_price_scaled, ask_quantity_scaled, bid_price_scaled, bid_quantity_scaled
-                    ));
-                }
-                (Some((ask_price, ask_quantity)), None) => {
-                    let ask_price_scaled = *ask_price / 1_000_000_000_000_000_000; // Convert to dollars
-                    let ask_quantity_scaled = *ask_quantity as f64 / 1e18;         // Convert to units
-
-                    output.push_str(&format!(
-                        "{:<15.2} -> {:<15.10} {:>30}\n",
-                        ask_price_scaled, ask_quantity_scaled, ""
-                    ));
+        for row in 0..asks.len().max(bids.len()) {
+            let ask = match asks.get(row) {
+                Some((price, quantity, cumulative)) => {
+                    let cell = format!(
+                        "{:<pw$} -> {:<sw$} | {:<sw$}",
+                        price, quantity, cumulative, pw = price_width, sw = size_width
+                    );
+                    match (self.color, row) {
+                        (true, 0) => paint(cell, BEST_ASK_COLOR),
+                        (true, _) => paint(cell, ASK_COLOR),
+                        (false, _) => cell,
+                    }
                 }
-                (None, Some((bid_price, bid_quantity))) => {
-                    let bid_price_scaled = *bid_price / 1_000_000_000_000_000_000; // Convert to dollars
-                    let bid_quantity_scaled = *bid_quantity as f64 / 1e18;         // Convert to units
-
-                    output.push_str(&format!(
-                        "{:<30} {:>15.2} -> {:>15.10}\n",
-                        "", bid_price_scaled, bid_quantity_scaled
-                    ));
+                None => format!("{:<width$}", "", width = side_width),
+            };
+            let bid = match bids.get(row) {
+                Some((price, quantity, cumulative)) => {
+                    let cell = format!(
+                        "{:>pw$} -> {:>sw$} | {:>sw$}",
+                        price, quantity, cumulative, pw = price_width, sw = size_width
+                    );
+                    match (self.color, row) {
+                        (true, 0) => paint(cell, BEST_BID_COLOR),
+                        (true, _) => paint(cell, BID_COLOR),
+                        (false, _) => cell,
+                    }
                 }
-                (None, None) => break,
-            }
+                None => String::new(),
+            };
+            output.push_str(&format!("{} {}\n", ask, bid));
         }
 
         output
@@ -243,3 +1462,433 @@ impl OrderBook {
 
 }
 
+impl fmt::Display for OrderBook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.visualize())
+    }
+}
+
+// A full map dump is unreadable for a deep book; show its shape and top of book instead
+impl fmt::Debug for OrderBook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OrderBook")
+            .field("product_id", &self.product_id)
+            .field("bid_levels", &self.bids.len())
+            .field("ask_levels", &self.asks.len())
+            .field("best_bid", &self.best_bid())
+            .field("best_ask", &self.best_ask())
+            .field("price_decimals", &self.price_decimals)
+            .field("size_decimals", &self.size_decimals)
+            .field("last_update_ts", &self.last_update_ts)
+            .field("last_max_timestamp", &self.last_max_timestamp)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Book at timestamp 10 with bids 99, 98 and asks 101, 102
+    fn book() -> OrderBook {
+        let mut book = OrderBook::new(18, 18).unwrap();
+        book.from_snapshot(MarketLiquidityResponse::new(10, vec![(99, 1), (98, 2)], vec![(101, 3), (102, 4)]))
+            .unwrap();
+        book
+    }
+
+    // Whole dollars or units at 18 decimals
+    fn x18(value: u128) -> u128 {
+        value * 10u128.pow(18)
+    }
+
+    // Bids 99x1 and 98x3, asks 101x2 and 102x2, in dollars and units at 18 decimals
+    fn analytics_book() -> OrderBook {
+        let mut book = OrderBook::new(18, 18).unwrap();
+        let bids = vec![(x18(99), x18(1)), (x18(98), x18(3))];
+        let asks = vec![(x18(101), x18(2)), (x18(102), x18(2))];
+        book.from_snapshot(MarketLiquidityResponse::new(10, bids, asks)).unwrap();
+        book
+    }
+
+    // Only a bid of 99x1, at 18 decimals
+    fn bids_only_book() -> OrderBook {
+        let mut book = OrderBook::new(18, 18).unwrap();
+        book.from_snapshot(MarketLiquidityResponse::new(10, vec![(x18(99), x18(1))], vec![])).unwrap();
+        book
+    }
+
+    #[test]
+    fn snapshot_populates_both_sides() {
+        let book = book();
+        assert_eq!(book.bids, BTreeMap::from([(98, 2), (99, 1)]));
+        assert_eq!(book.asks, BTreeMap::from([(101, 3), (102, 4)]));
+        assert_eq!(book.last_update_ts(), 10);
+        assert_eq!(book.validate_orderbook(), Ok(()));
+    }
+
+    #[test]
+    fn zero_quantity_deletes_level() {
+        let mut book = book();
+        let update = BookDepthResponse::new(10, 11, ProductId(2), vec![(99, 0)], vec![(102, 0)]);
+        assert_eq!(book.update(update), Ok(UpdateResult::Applied));
+        assert_eq!(book.bids, BTreeMap::from([(98, 2)]));
+        assert_eq!(book.asks, BTreeMap::from([(101, 3)]));
+        assert_eq!(book.validate_orderbook(), Ok(()));
+    }
+
+    #[test]
+    fn update_crossing_the_book_fails_validation() {
+        let mut book = book();
+        let update = BookDepthResponse::new(10, 11, ProductId(2), vec![(101, 5)], vec![]);
+        let crossed = OrderBookError::CrossedBook { highest_bid: 101, lowest_ask: 101 };
+        assert_eq!(book.update(update), Err(crossed.clone()));
+        assert_eq!(book.bids, BTreeMap::from([(98, 2), (99, 1), (101, 5)]));
+        assert_eq!(book.asks, BTreeMap::from([(101, 3), (102, 4)]));
+        assert_eq!(book.validate_orderbook(), Err(crossed));
+    }
+
+    #[test]
+    fn zero_quantity_on_missing_level_is_a_no_op() {
+        let mut book = book();
+        let update = BookDepthResponse::new(10, 11, ProductId(2), vec![(97, 0)], vec![(103, 0)]);
+        assert_eq!(book.update(update), Ok(UpdateResult::Applied));
+        assert_eq!(book.bids, BTreeMap::from([(98, 2), (99, 1)]));
+        assert_eq!(book.asks, BTreeMap::from([(101, 3), (102, 4)]));
+        assert_eq!(book.validate_orderbook(), Ok(()));
+    }
+
+    #[test]
+    fn stale_snapshot_does_not_move_the_book_backwards() {
+        let mut book = book();
+        let update = BookDepthResponse::new(10, 20, ProductId(2), vec![(99, 7)], vec![(101, 0)]);
+        assert_eq!(book.update(update), Ok(UpdateResult::Applied));
+
+        let stale = MarketLiquidityResponse::new(15, vec![(90, 1)], vec![(110, 1)]);
+        assert_eq!(book.from_snapshot(stale), Err(OrderBookError::StaleSnapshot { snapshot_ts: 15, book_ts: 20 }));
+        assert_eq!(book.bids, BTreeMap::from([(98, 2), (99, 7)]));
+        assert_eq!(book.asks, BTreeMap::from([(102, 4)]));
+        assert_eq!(book.last_update_ts(), 20);
+        assert_eq!(book.last_update_timestamp(), Some(20));
+    }
+
+    #[test]
+    fn vwap_and_notional_near_u128_max_do_not_overflow() {
+        let mut book = OrderBook::new(18, 18).unwrap();
+        book.bids = BTreeMap::from([(u128::MAX - 1, u128::MAX), (u128::MAX, u128::MAX)]);
+
+        assert_eq!(book.vwap_raw(Side::Bid, 1), Some(u128::MAX)); // u128::MAX² alone fits in U256
+        assert_eq!(book.vwap_raw(Side::Bid, 2), None); // two of them don't
+        assert_eq!(book.total_bid_notional(), U256::MAX);
+    }
+
+    #[test]
+    fn parses_signed_fixed_point_strings() {
+        assert_eq!(parse_i128_str("0"), Ok(0));
+        assert_eq!(parse_i128_str("-0"), Ok(0));
+        assert_eq!(parse_i128_str("-1500000000000000000"), Ok(-1_500_000_000_000_000_000));
+        assert_eq!(parse_i128_str(&i128::MAX.to_string()), Ok(i128::MAX));
+        assert_eq!(parse_i128_str(&i128::MIN.to_string()), Ok(i128::MIN));
+    }
+
+    #[test]
+    fn rejects_signed_values_out_of_range() {
+        let above_max = (i128::MAX as u128 + 1).to_string(); // 2^127
+        let below_min = format!("-{}", i128::MAX as u128 + 2); // -(2^127 + 1)
+        assert_eq!(
+            parse_i128_str(&above_max),
+            Err(format!("invalid fixed-point value {:?}: out of range", above_max))
+        );
+        assert_eq!(
+            parse_i128_str(&below_min),
+            Err(format!("invalid fixed-point value {:?}: out of range", below_min))
+        );
+        assert_eq!(parse_i128_str("-abc"), Err("invalid fixed-point value \"-abc\"".to_string()));
+    }
+
+    #[test]
+    fn deserializes_signed_fixed_point_strings() {
+        assert_eq!(deserialize_i128_str(serde_json::json!("-42")).unwrap(), -42);
+        assert_eq!(deserialize_i128_str(serde_json::json!(i128::MIN.to_string())).unwrap(), i128::MIN);
+
+        let above_max = (i128::MAX as u128 + 1).to_string();
+        let error = deserialize_i128_str(serde_json::json!(above_max)).unwrap_err();
+        assert_eq!(error.to_string(), format!("invalid fixed-point value {:?}: out of range", above_max));
+        assert!(deserialize_i128_str(serde_json::json!(-42)).is_err()); // a number, not a string
+    }
+
+    #[test]
+    fn decimals_beyond_what_a_u128_can_scale_by_are_rejected() {
+        assert!(OrderBook::new(38, 0).is_ok());
+        assert!(matches!(OrderBook::new(39, 18), Err(OrderBookError::InvalidDecimals { decimals: 39 })));
+        assert!(matches!(OrderBook::new(18, 64), Err(OrderBookError::InvalidDecimals { decimals: 64 })));
+
+        let json = book().to_json().replace(r#""price_decimals":18"#, r#""price_decimals":39"#);
+        let error = OrderBook::from_json(&json).unwrap_err();
+        assert!(error.to_string().contains("Invalid Decimals: 39"), "{}", error);
+    }
+
+    #[test]
+    fn summary_starts_with_the_product() {
+        let mut book = OrderBook::new(0, 0).unwrap();
+        book.from_snapshot(MarketLiquidityResponse::new(10, vec![(99, 1)], vec![(101, 3)])).unwrap();
+        assert_eq!(book.summary(), "product=N/A bid=99.00x1.0000000000 ask=101.00x3.0000000000 spread=2.00 mid=100.00");
+        book.set_product_id(Some(ProductId(2)));
+        assert_eq!(book.summary(), "product=2 bid=99.00x1.0000000000 ask=101.00x3.0000000000 spread=2.00 mid=100.00");
+    }
+
+    #[test]
+    fn summary_formats_sizes_with_the_size_precision() {
+        let mut book = OrderBook::new(18, 18).unwrap();
+        let (price, size) = (99_500_000_000_000_000_000u128, 1_234_400_000_000_000_000u128);
+        book.from_snapshot(MarketLiquidityResponse::new(10, vec![(price, size)], vec![])).unwrap();
+        book.set_display_precision(DisplayPrecision { price: 1, size: 3 });
+        assert_eq!(book.summary(), "product=N/A bid=99.5x1.234 ask=N/A spread=N/A mid=N/A");
+    }
+
+    #[test]
+    fn symbol_is_shown_by_summary_and_visualize() {
+        let mut book = OrderBook::new(0, 0).unwrap();
+        book.from_snapshot(MarketLiquidityResponse::new(10, vec![(99, 1)], vec![(101, 3)])).unwrap();
+        book.set_product_id(Some(ProductId(2)));
+        assert!(book.visualize().starts_with("Order Book: product 2\n"));
+
+        book.set_symbol(Some("BTC-USDC-PERP".to_string()));
+        let summary = "product=2 symbol=BTC-USDC-PERP bid=99.00x1.0000000000 ask=101.00x3.0000000000 spread=2.00 mid=100.00";
+        assert_eq!(book.summary(), summary);
+        assert!(book.visualize().starts_with("Order Book: BTC-USDC-PERP\n"));
+    }
+
+    #[test]
+    fn liquidity_bands_saturate_instead_of_overflowing() {
+        let mut book = OrderBook::new(0, 0).unwrap();
+        book.bids = BTreeMap::from([(98, u128::MAX), (99, u128::MAX)]);
+        book.asks = BTreeMap::from([(101, 1)]);
+        assert_eq!(book.liquidity_bands(Side::Bid, &[100, 500]), vec![(100, u128::MAX), (500, u128::MAX)]);
+    }
+
+    #[test]
+    fn event_covered_by_the_book_is_stale() {
+        let mut book = book();
+        let covered = BookDepthResponse::new(8, 10, ProductId(2), vec![(97, 1)], vec![]);
+        assert_eq!(book.update(covered), Ok(UpdateResult::Stale));
+        assert_eq!(book.bids, BTreeMap::from([(98, 2), (99, 1)]));
+        assert_eq!(book.last_update_ts(), 10);
+    }
+
+    #[test]
+    fn first_event_may_straddle_the_snapshot() {
+        let mut book = book();
+        let straddling = BookDepthResponse::new(8, 12, ProductId(2), vec![(97, 1)], vec![]);
+        assert_eq!(book.update(straddling), Ok(UpdateResult::Applied));
+        assert_eq!(book.last_update_ts(), 12);
+    }
+
+    #[test]
+    fn first_event_newer_than_the_snapshot_is_a_gap() {
+        let mut book = book();
+        let after_a_gap = BookDepthResponse::new(11, 12, ProductId(2), vec![(97, 1)], vec![]); // 10..11 was missed
+        assert_eq!(book.update(after_a_gap), Ok(UpdateResult::GapDetected { expected: 10, got: 11 }));
+        assert_eq!(book.bids, BTreeMap::from([(98, 2), (99, 1)]));
+        assert_eq!(book.last_update_ts(), 10);
+    }
+
+    #[test]
+    fn event_not_chaining_onto_the_last_one_is_a_gap() {
+        let mut book = book();
+        assert_eq!(book.update(BookDepthResponse::new(10, 11, ProductId(2), vec![], vec![])), Ok(UpdateResult::Applied));
+        assert_eq!(book.update(BookDepthResponse::new(11, 12, ProductId(2), vec![], vec![])), Ok(UpdateResult::Applied));
+        let after_a_gap = BookDepthResponse::new(13, 14, ProductId(2), vec![(97, 1)], vec![]);
+        assert_eq!(book.update(after_a_gap), Ok(UpdateResult::GapDetected { expected: 12, got: 13 }));
+        assert_eq!(book.bids, BTreeMap::from([(98, 2), (99, 1)]));
+        assert_eq!(book.last_update_ts(), 12);
+    }
+
+    #[test]
+    fn decimal_notation_is_rejected() {
+        let ambiguous = "decimal and scientific notation are ambiguous, expected raw fixed-point digits";
+        assert_eq!(parse_u128_str("43210.0"), Err(format!("invalid fixed-point value \"43210.0\": {}", ambiguous)));
+        assert_eq!(parse_u128_str("43210000000000000000000"), Ok(43_210_000_000_000_000_000_000));
+        assert!(parse_i128_str("-43210.5").is_err());
+        assert!(serde_json::from_str::<MarketLiquidityResponse>(&snapshot_json("43210.0")).is_err());
+    }
+
+    #[test]
+    fn scientific_notation_is_rejected() {
+        let ambiguous = "decimal and scientific notation are ambiguous, expected raw fixed-point digits";
+        assert_eq!(parse_u128_str("4.321e4"), Err(format!("invalid fixed-point value \"4.321e4\": {}", ambiguous)));
+        assert_eq!(parse_u128_str("4321E1"), Err(format!("invalid fixed-point value \"4321E1\": {}", ambiguous)));
+        assert!(parse_i128_str("-1.5e18").is_err());
+        assert!(serde_json::from_str::<MarketLiquidityResponse>(&snapshot_json("4.321e22")).is_err());
+    }
+
+    #[test]
+    fn other_invalid_unsigned_values_are_named() {
+        assert_eq!(parse_u128_str("-1"), Err("invalid fixed-point value \"-1\": expected an unsigned number".to_string()));
+        let too_big = format!("{}0", u128::MAX);
+        assert_eq!(parse_u128_str(&too_big), Err(format!("invalid fixed-point value {:?}: out of range", too_big)));
+    }
+
+    // A market liquidity reply with one bid at `price`
+    fn snapshot_json(price: &str) -> String {
+        format!(
+            r#"{{"status":"success","data":{{"bids":[["{}","1"]],"asks":[],"timestamp":"1"}},"request_type":"query_market_liquidity"}}"#,
+            price
+        )
+    }
+
+    #[test]
+    fn depth_for_size_walks_the_book_from_the_top() {
+        let book = analytics_book();
+        // buying 3 lifts 2 at 101 and 1 at 102
+        assert_eq!(book.depth_for_size(Side::Ask, x18(3)), Some((x18(304), 101_333_333_333_333_333_333)));
+        // selling 4 takes the whole bid side: 1 at 99 and 3 at 98
+        assert_eq!(book.depth_for_size(Side::Bid, x18(4)), Some((x18(393), x18(9825) / 100)));
+        // part of the top level
+        assert_eq!(book.depth_for_size(Side::Bid, x18(1) / 2), Some((x18(99) / 2, x18(99))));
+    }
+
+    #[test]
+    fn depth_for_size_beyond_the_book_is_none() {
+        let book = analytics_book();
+        assert_eq!(book.depth_for_size(Side::Ask, x18(4) + 1), None); // one raw unit more than the asks hold
+        assert_eq!(book.depth_for_size(Side::Bid, 0), None);
+        assert_eq!(bids_only_book().depth_for_size(Side::Ask, 1), None); // empty side
+    }
+
+    #[test]
+    fn vwap_weights_the_top_levels_by_quantity() {
+        let book = analytics_book();
+        assert_eq!(book.vwap(Side::Bid, 1), Some(99.0));
+        assert_eq!(book.vwap(Side::Bid, 2), Some(98.25)); // (99 * 1 + 98 * 3) / 4
+        assert_eq!(book.vwap(Side::Bid, 10), Some(98.25)); // deeper than the side: all of it
+        assert_eq!(book.vwap_raw(Side::Ask, 2), Some(x18(1015) / 10)); // (101 * 2 + 102 * 2) / 4
+        assert_eq!(book.mid_vwap(2), Some(99.875));
+    }
+
+    #[test]
+    fn vwap_of_no_levels_is_none() {
+        let book = analytics_book();
+        assert_eq!(book.vwap(Side::Bid, 0), None);
+        assert_eq!(book.mid_vwap(0), None);
+        assert_eq!(bids_only_book().vwap(Side::Ask, 5), None); // empty side
+        assert_eq!(bids_only_book().mid_vwap(5), None);
+    }
+
+    #[test]
+    fn imbalance_compares_bid_and_ask_volume() {
+        let book = analytics_book();
+        assert_eq!(book.imbalance(1), Some(-1.0 / 3.0)); // (1 - 2) / (1 + 2)
+        assert_eq!(book.imbalance(2), Some(0.0)); // 4 on each side
+        assert_eq!(book.imbalance(10), Some(0.0)); // deeper than either side: all of both
+        assert_eq!(bids_only_book().imbalance(5), Some(1.0));
+    }
+
+    #[test]
+    fn imbalance_of_no_levels_is_none() {
+        assert_eq!(analytics_book().imbalance(0), None);
+        assert_eq!(OrderBook::new(18, 18).unwrap().imbalance(5), None);
+    }
+
+    #[test]
+    fn update_with_changes_reports_only_levels_that_changed() {
+        let mut book = book();
+        let event = BookDepthResponse::new(
+            10,
+            11,
+            ProductId(2),
+            vec![(99, 5), (98, 0), (97, 1), (96, 0)], // modified, deleted, added, deleted though absent
+            vec![(101, 3)],                           // unchanged
+        );
+        let (result, changes) = book.update_with_changes(event).unwrap();
+        assert_eq!(result, UpdateResult::Applied);
+        assert_eq!(
+            changes,
+            vec![
+                LevelChange { side: Side::Bid, price: 99, old_qty: 1, new_qty: 5 },
+                LevelChange { side: Side::Bid, price: 98, old_qty: 2, new_qty: 0 },
+                LevelChange { side: Side::Bid, price: 97, old_qty: 0, new_qty: 1 },
+            ]
+        );
+    }
+
+    #[test]
+    fn update_with_changes_reports_nothing_unless_applied() {
+        let mut book = book();
+        let stale = BookDepthResponse::new(9, 10, ProductId(2), vec![(99, 5)], vec![]);
+        assert_eq!(book.update_with_changes(stale).unwrap(), (UpdateResult::Stale, Vec::new()));
+        let gap = BookDepthResponse::new(12, 13, ProductId(2), vec![(99, 5)], vec![]);
+        let (result, changes) = book.update_with_changes(gap).unwrap();
+        assert_eq!(result, UpdateResult::GapDetected { expected: 10, got: 12 });
+        assert!(changes.is_empty());
+        let empty = BookDepthResponse::new(10, 11, ProductId(2), vec![], vec![]);
+        assert_eq!(book.update_with_changes(empty).unwrap(), (UpdateResult::Applied, Vec::new()));
+    }
+
+    #[test]
+    fn diff_lists_the_changes_from_one_book_to_another() {
+        let ours = book();
+        let mut theirs = book();
+        let event = BookDepthResponse::new(10, 11, ProductId(2), vec![(99, 0), (97, 5)], vec![(101, 4)]);
+        theirs.update(event).unwrap();
+
+        let bid = |price, old_qty, new_qty| LevelChange { side: Side::Bid, price, old_qty, new_qty };
+        let ask = |price, old_qty, new_qty| LevelChange { side: Side::Ask, price, old_qty, new_qty };
+        assert_eq!(ours.diff(&theirs), vec![bid(97, 0, 5), bid(99, 1, 0), ask(101, 3, 4)]);
+        assert_eq!(theirs.diff(&ours), vec![bid(97, 5, 0), bid(99, 0, 1), ask(101, 4, 3)]);
+    }
+
+    #[test]
+    fn diff_against_an_empty_book_lists_every_level() {
+        let empty = OrderBook::new(18, 18).unwrap();
+        let bid = |price, old_qty, new_qty| LevelChange { side: Side::Bid, price, old_qty, new_qty };
+        let ask = |price, old_qty, new_qty| LevelChange { side: Side::Ask, price, old_qty, new_qty };
+        assert_eq!(empty.diff(&book()), vec![bid(98, 0, 2), bid(99, 0, 1), ask(101, 0, 3), ask(102, 0, 4)]);
+        assert_eq!(book().diff(&book()), Vec::new());
+        assert_eq!(empty.diff(&empty), Vec::new());
+    }
+
+    #[test]
+    fn microprice_leans_towards_the_thinner_side() {
+        // (99 * 2 + 101 * 1) / (1 + 2): the bid is thinner, so the price leans towards it
+        let microprice = analytics_book().microprice().unwrap();
+        assert!((microprice - 299.0 / 3.0).abs() < 1e-12, "{}", microprice);
+
+        let mut balanced = OrderBook::new(18, 18).unwrap();
+        let (bids, asks) = (vec![(x18(99), x18(2))], vec![(x18(101), x18(2))]);
+        balanced.from_snapshot(MarketLiquidityResponse::new(10, bids, asks)).unwrap();
+        assert_eq!(balanced.microprice(), Some(100.0)); // equal sizes: the mid
+    }
+
+    #[test]
+    fn microprice_of_a_one_sided_book_is_none() {
+        assert_eq!(bids_only_book().microprice(), None);
+        assert_eq!(OrderBook::new(18, 18).unwrap().microprice(), None);
+    }
+
+    #[test]
+    fn volume_through_price_sums_levels_at_or_better_than_the_limit() {
+        let book = analytics_book();
+        assert_eq!(book.volume_through_price(Side::Ask, x18(101)), x18(2)); // a buy at 101 takes only that level
+        assert_eq!(book.volume_through_price(Side::Ask, x18(1000)), x18(4)); // through the whole side
+        assert_eq!(book.volume_through_price(Side::Bid, x18(98)), x18(4));
+        assert_eq!(book.volume_through_price(Side::Bid, x18(99)), x18(1));
+    }
+
+    #[test]
+    fn volume_through_price_short_of_the_book_is_zero() {
+        let book = analytics_book();
+        assert_eq!(book.volume_through_price(Side::Ask, x18(100)), 0); // below the best ask
+        assert_eq!(book.volume_through_price(Side::Bid, x18(100)), 0); // above the best bid
+        assert_eq!(bids_only_book().volume_through_price(Side::Ask, u128::MAX), 0); // empty side
+    }
+
+    #[test]
+    fn levels_run_from_the_top_of_book_outwards() {
+        let book = book();
+        assert_eq!(book.levels(Side::Bid).collect::<Vec<_>>(), vec![(&99, &1), (&98, &2)]);
+        assert_eq!(book.levels(Side::Ask).collect::<Vec<_>>(), vec![(&101, &3), (&102, &4)]);
+        assert_eq!(book.levels(Side::Bid).size_hint(), (2, Some(2)));
+        assert_eq!(OrderBook::new(18, 18).unwrap().levels(Side::Ask).next(), None);
+    }
+}
@@ -0,0 +1,289 @@
+use std::collections::{HashMap, VecDeque};
+use std::mem;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::select;
+use tokio::sync::mpsc::Receiver;
+use tracing::{debug, info, trace, warn};
+use crate::builder::query_market_liquidity;
+use crate::listener::{validate_depth, GatewayClient, QueryError};
+use crate::model::{BookDepthResponse, OrderBook, ProductId, StreamResponseType, UpdateResult, ValidationMode};
+use crate::stats::Stats;
+use crate::transport::{TungsteniteTransport, WsTransport};
+
+/// Receives each product's book after every update applied to it (and after invalid updates in
+/// `ValidationMode::Lenient`).  Closures taking `(ProductId, &OrderBook, UpdateResult)` implement it too.
+pub trait MultiBookObserver {
+    fn on_update(&mut self, product_id: ProductId, order_book: &OrderBook, result: UpdateResult);
+
+    /// The stream reconnected: every book is stale until its resnapshot lands
+    fn on_reconnect(&mut self) {}
+}
+
+impl<F: FnMut(ProductId, &OrderBook, UpdateResult)> MultiBookObserver for F {
+    fn on_update(&mut self, product_id: ProductId, order_book: &OrderBook, result: UpdateResult) {
+        self(product_id, order_book, result)
+    }
+}
+
+/// Maintains one `OrderBook` per product from a single book_depth stream carrying several products
+/// (see `Subscriptions`), like `OrderBookBuilder` does for one.  Each event is routed to its product's book by
+/// `product_id`, and each book detects gaps and resnapshots on its own: while one product's snapshot is in
+/// flight its events are queued, and every other product's keep being applied.
+///
+/// Snapshots share one gateway client, so they are fetched one product at a time.
+pub struct MultiBook<T: WsTransport = TungsteniteTransport> {
+    gateway: GatewayClient<T>,
+    depth: usize, // how deep to fill each order book up from snapshot (max 100)
+    books: Books,
+}
+
+// Everything but the gateway, so events can be applied while a snapshot query holds the client
+struct Books {
+    books: HashMap<ProductId, ProductBook>,
+    validation_mode: ValidationMode,
+    stats: Arc<Stats>,
+}
+
+struct ProductBook {
+    order_book: OrderBook,
+    needs_snapshot: bool, // events are queued until a snapshot lands
+    queue: VecDeque<BookDepthResponse>,
+}
+
+impl<T: WsTransport> MultiBook<T> {
+    /// Fails if `depth` isn't a valid market liquidity depth (1..=100)
+    pub fn new(gateway: GatewayClient<T>, depth: usize, validation_mode: ValidationMode) -> Result<Self, QueryError> {
+        validate_depth(depth)?;
+        Ok(MultiBook {
+            gateway,
+            depth,
+            books: Books {
+                books: HashMap::new(),
+                validation_mode,
+                stats: Arc::default(),
+            },
+        })
+    }
+
+    /// Maintain a book for `product_id`, starting from `order_book` (configured with the product's decimals,
+    /// tick size, ...).  Replaces any book already kept for it.  Events for products without a book are dropped.
+    pub fn add_book(&mut self, product_id: ProductId, mut order_book: OrderBook) {
+        order_book.set_product_id(Some(product_id));
+        let book = ProductBook {
+            order_book,
+            needs_snapshot: true,
+            queue: VecDeque::new(),
+        };
+        self.books.books.insert(product_id, book);
+    }
+
+    pub fn book(&self, product_id: ProductId) -> Option<&OrderBook> {
+        self.books.books.get(&product_id).map(|book| &book.order_book)
+    }
+
+    pub fn books(&self) -> impl Iterator<Item = (ProductId, &OrderBook)> + '_ {
+        self.books.books.iter().map(|(product_id, book)| (*product_id, &book.order_book))
+    }
+
+    /// Counters for every book together; hand a clone to `Subscribe` so parse errors are counted too
+    pub fn stats(&self) -> Arc<Stats> {
+        self.books.stats.clone()
+    }
+
+    /// Consume book_depth events from `receiver`, handing a product's book to `observer` after every update
+    /// applied to it.  Snapshots every book first, then resnapshots each one whenever it falls out of sync.
+    #[tracing::instrument(name = "multi_book", skip_all, fields(products = self.books.books.len()))]
+    pub async fn run<O>(&mut self, mut receiver: Receiver<StreamResponseType>, mut observer: O)
+    where
+        O: MultiBookObserver,
+    {
+        loop {
+            while let Some(product_id) = self.books.next_needing_snapshot() {
+                self.load_snapshot(product_id, &mut receiver, &mut observer).await;
+            }
+
+            match receiver.recv().await {
+                Some(event) => self.books.handle(event, &mut observer),
+                None => break,
+            }
+        }
+    }
+
+    // Repopulate `product_id`'s book from a fresh snapshot, then apply the events queued for it meanwhile.
+    // Other products' events are applied as they arrive, so one resnapshot doesn't stall every book.
+    async fn load_snapshot<O: MultiBookObserver>(
+        &mut self,
+        product_id: ProductId,
+        receiver: &mut Receiver<StreamResponseType>,
+        observer: &mut O,
+    ) {
+        let mut reconnected = false;
+        let snapshot = {
+            let query = query_market_liquidity(&mut self.gateway, product_id, self.depth);
+            tokio::pin!(query);
+            loop {
+                select! {
+                    snapshot = &mut query => break snapshot,
+                    Some(event) = receiver.recv() => {
+                        reconnected |= matches!(event, StreamResponseType::Reconnected);
+                        self.books.handle(event, observer);
+                    }
+                }
+            }
+        };
+        // `handle` flagged every book, this one included, and told the observer: the snapshot may predate the
+        // disconnect, so leave it to the next round
+        if reconnected {
+            return;
+        }
+
+        let book = match self.books.books.get_mut(&product_id) {
+            Some(book) => book,
+            None => return,
+        };
+        // an invalid or stale snapshot can't be trusted; the book still needs one, so the next round fetches another
+        if let Err(e) = book.order_book.from_snapshot(snapshot) {
+            warn!(%product_id, error = %e, "invalid snapshot, retrying");
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            return;
+        }
+        book.needs_snapshot = false;
+        for data in mem::take(&mut book.queue) {
+            self.books.apply(data, observer); // requeued if it finds a gap
+        }
+    }
+}
+
+impl Books {
+    fn next_needing_snapshot(&self) -> Option<ProductId> {
+        self.books
+            .iter()
+            .find(|(_, book)| book.needs_snapshot)
+            .map(|(product_id, _)| *product_id)
+    }
+
+    fn handle<O: MultiBookObserver>(&mut self, event: StreamResponseType, observer: &mut O) {
+        match event {
+            StreamResponseType::BookDepth(data) => self.apply(data, observer),
+            StreamResponseType::Reconnected => {
+                // whatever was sent while disconnected is lost, for every product
+                observer.on_reconnect();
+                info!("stream reconnected, retrieving snapshots");
+                for book in self.books.values_mut() {
+                    self.stats.record_resnapshot();
+                    book.needs_snapshot = true;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn apply<O: MultiBookObserver>(&mut self, data: BookDepthResponse, observer: &mut O) {
+        let product_id = data.product_id;
+        let book = match self.books.get_mut(&product_id) {
+            Some(book) => book,
+            None => {
+                self.stats.record_update_dropped();
+                debug!(%product_id, "dropping book depth update for a product without a book");
+                return;
+            }
+        };
+        if book.needs_snapshot {
+            book.queue.push_back(data);
+            return;
+        }
+
+        match book.order_book.update(data) {
+            Ok(UpdateResult::Applied) => {
+                self.stats.record_update_applied();
+                trace!(%product_id, last_update_ts = book.order_book.last_update_ts(), "applied book depth update");
+                observer.on_update(product_id, &book.order_book, UpdateResult::Applied);
+            }
+            Ok(UpdateResult::Stale) => self.stats.record_update_dropped(), // drop msgs from before the snapshot
+            Ok(UpdateResult::GapDetected { expected, got }) => {
+                self.stats.record_gap();
+                self.stats.record_resnapshot();
+                info!(%product_id, expected, got, "dropped a book depth update, retrieving snapshot");
+                book.needs_snapshot = true;
+            }
+            // malformed event: nothing was applied, but its changes are missing from the book now
+            Err(e) if e.is_malformed_update() => {
+                self.stats.record_update_dropped();
+                self.stats.record_resnapshot();
+                warn!(%product_id, error = %e, "skipping malformed book depth update, retrieving snapshot");
+                book.needs_snapshot = true;
+            }
+            Err(e) => match self.validation_mode {
+                ValidationMode::Strict => {
+                    warn!(%product_id, error = %e, "invalid order book, retrieving snapshot");
+                    self.stats.record_resnapshot();
+                    book.needs_snapshot = true;
+                }
+                ValidationMode::Lenient => {
+                    warn!(%product_id, error = %e, "invalid order book");
+                    observer.on_update(product_id, &book.order_book, UpdateResult::Applied); // applied, but failed validation
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::transport::MockTransport;
+
+    fn snapshot(timestamp: u32, bid: (u32, u32)) -> String {
+        format!(
+            r#"{{"status":"success","data":{{"bids":[["{}","{}"]],"asks":[["101","1"]],"timestamp":"{}"}},"request_type":"query_market_liquidity"}}"#,
+            bid.0, bid.1, timestamp
+        )
+    }
+
+    #[tokio::test]
+    async fn malformed_first_event_after_a_snapshot_triggers_a_resnapshot() {
+        let config = Config::default();
+        let (first, second) = (snapshot(1, (99, 1)), snapshot(3, (98, 2)));
+        let gateway = MockTransport::from_text(vec![vec![first.as_str(), second.as_str()]]);
+        let client = GatewayClient::with_transport(gateway.clone(), "ws://gateway", config.backoff, 0, Duration::from_secs(1), 0.0);
+        let mut books = MultiBook::new(client, 10, ValidationMode::Strict).unwrap();
+        books.add_book(ProductId(2), OrderBook::new(18, 18).unwrap());
+
+        let (sender, receiver) = tokio::sync::mpsc::channel(10);
+        let malformed = BookDepthResponse::new(1, 2, ProductId(2), vec![(98, 1), (98, 2)], vec![]); // price listed twice
+        sender.send(StreamResponseType::BookDepth(malformed)).await.unwrap();
+        let next = BookDepthResponse::new(2, 3, ProductId(2), vec![(97, 1)], vec![]); // chains onto the dropped event
+        sender.send(StreamResponseType::BookDepth(next)).await.unwrap();
+        drop(sender);
+        books.run(receiver, |_: ProductId, _: &OrderBook, _: UpdateResult| {}).await;
+
+        assert_eq!(gateway.sent().len(), 2);
+        let book = books.book(ProductId(2)).unwrap();
+        assert_eq!(book.best_bid(), Some((98, 2)));
+        assert_eq!(book.num_bid_levels(), 1);
+    }
+
+    #[tokio::test]
+    async fn reconnect_while_a_snapshot_is_in_flight_triggers_another() {
+        let mut config = Config::default();
+        config.backoff.base = Duration::from_millis(10);
+        let (first, second) = (snapshot(1, (99, 1)), snapshot(5, (98, 2)));
+        // the first attempt finds the socket closed, so the query is still in flight when the reconnect is read
+        let gateway = MockTransport::from_text(vec![vec![], vec![first.as_str(), second.as_str()]]);
+        let client = GatewayClient::with_transport(gateway.clone(), "ws://gateway", config.backoff, 1, Duration::from_secs(1), 0.0);
+        let mut books = MultiBook::new(client, 10, ValidationMode::Strict).unwrap();
+        books.add_book(ProductId(2), OrderBook::new(18, 18).unwrap());
+
+        let (sender, receiver) = tokio::sync::mpsc::channel(10);
+        sender.send(StreamResponseType::Reconnected).await.unwrap();
+        drop(sender);
+        books.run(receiver, |_: ProductId, _: &OrderBook, _: UpdateResult| {}).await;
+
+        assert_eq!(gateway.sent().len(), 3); // the failed attempt, the snapshot from before the reconnect, and another
+        let book = books.book(ProductId(2)).unwrap();
+        assert_eq!(book.last_update_ts(), 5);
+        assert_eq!(book.best_bid(), Some((98, 2)));
+    }
+}
@@ -0,0 +1,143 @@
+use std::env;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{lookup_host, TcpSocket, TcpStream};
+use tokio_socks::tcp::Socks5Stream;
+use tokio_tungstenite::client_async_tls_with_config;
+use tokio_tungstenite::tungstenite::{self, handshake::client::Response, http::Uri, protocol::WebSocketConfig};
+use crate::listener::WsStream;
+
+const MAX_CONNECT_RESPONSE: usize = 8192; // bytes of CONNECT response headers we're willing to read
+
+/// Proxy from the environment: `HTTPS_PROXY`, then `ALL_PROXY` (either case).  None if unset or empty.
+pub fn proxy_from_env() -> Option<String> {
+    ["HTTPS_PROXY", "https_proxy", "ALL_PROXY", "all_proxy"]
+        .iter()
+        .filter_map(|var| env::var(var).ok())
+        .find(|value| !value.is_empty())
+}
+
+/// Open a websocket to `url`, tunneling through `proxy` when given.
+/// Supports `http://host:port` (HTTP CONNECT) and `socks5://host:port` / `socks5h://host:port` proxies, without credentials.
+/// With `bind`, the TCP connection (to the proxy, if any) originates from that local address, e.g. to pick
+/// the interface on a multi-homed host.
+pub async fn connect(
+    url: &str,
+    config: WebSocketConfig,
+    proxy: Option<&str>,
+    bind: Option<IpAddr>,
+) -> Result<(WsStream, Response), tungstenite::Error> {
+    if proxy.is_none() && bind.is_none() {
+        return tokio_tungstenite::connect_async_with_config(url, Some(config)).await;
+    }
+
+    let target: Uri = url.parse()?;
+    let host = target.host().ok_or(tungstenite::Error::Url(tungstenite::error::UrlError::NoHostName))?;
+    let port = target.port_u16().unwrap_or(match target.scheme_str() {
+        Some("wss") => 443,
+        _ => 80,
+    });
+
+    let stream = match proxy {
+        Some(proxy) => tunnel(proxy, host, port, bind).await?,
+        None => tcp_connect(host, port, bind).await?,
+    };
+    client_async_tls_with_config(url, stream, Some(config), None).await
+}
+
+// TCP stream to `host:port` through `proxy`
+async fn tunnel(proxy: &str, host: &str, port: u16, bind: Option<IpAddr>) -> io::Result<TcpStream> {
+    let proxy_uri: Uri = proxy.parse().map_err(|e| invalid_proxy(proxy, e))?;
+    let proxy_host = proxy_uri.host().ok_or_else(|| invalid_proxy(proxy, "missing host"))?;
+    let proxy_port = proxy_port(&proxy_uri);
+
+    match proxy_uri.scheme_str() {
+        Some("http") | None => http_connect(tcp_connect(proxy_host, proxy_port, bind).await?, host, port).await,
+        Some("socks5") | Some("socks5h") => {
+            Socks5Stream::connect_with_socket(tcp_connect(proxy_host, proxy_port, bind).await?, (host, port))
+                .await
+                .map(Socks5Stream::into_inner)
+                .map_err(io::Error::other)
+        }
+        Some(scheme) => Err(invalid_proxy(proxy, format!("unsupported scheme {}", scheme))),
+    }
+}
+
+// The proxy's port, defaulting to the scheme's usual one: 1080 for SOCKS, 80 for HTTP
+fn proxy_port(proxy_uri: &Uri) -> u16 {
+    proxy_uri.port_u16().unwrap_or(match proxy_uri.scheme_str() {
+        Some("socks5") | Some("socks5h") => 1080,
+        _ => 80,
+    })
+}
+
+// TCP stream to `host:port`, from the local address `bind` when given.  Only addresses of `bind`'s family are tried.
+async fn tcp_connect(host: &str, port: u16, bind: Option<IpAddr>) -> io::Result<TcpStream> {
+    let host = host.trim_start_matches('[').trim_end_matches(']'); // IPv6 literals come bracketed in urls
+    let bind = match bind {
+        Some(bind) => bind,
+        None => return TcpStream::connect((host, port)).await,
+    };
+
+    let mut last_error = None;
+    for addr in lookup_host((host, port)).await? {
+        if addr.is_ipv4() != bind.is_ipv4() {
+            continue;
+        }
+        let socket = if addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+        socket.bind(SocketAddr::new(bind, 0))?;
+        match socket.connect(addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(io::ErrorKind::AddrNotAvailable, format!("{} has no address reachable from {}", host, bind))
+    }))
+}
+
+// HTTP CONNECT tunnel: everything after the proxy's 200 response goes straight to `host:port`
+async fn http_connect(mut stream: TcpStream, host: &str, port: u16) -> io::Result<TcpStream> {
+    let request = format!("CONNECT {host}:{port} HTTP/1.1\r\nHost: {host}:{port}\r\n\r\n", host = host, port = port);
+    stream.write_all(request.as_bytes()).await?;
+
+    // read byte by byte so nothing past the headers is consumed
+    let mut response = Vec::new();
+    let mut byte = [0u8; 1];
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= MAX_CONNECT_RESPONSE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "proxy CONNECT response too long"));
+        }
+        if stream.read(&mut byte).await? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "proxy closed the connection during CONNECT"));
+        }
+        response.push(byte[0]);
+    }
+
+    let status_line = String::from_utf8_lossy(&response);
+    let status_line = status_line.lines().next().unwrap_or_default();
+    match status_line.split_whitespace().nth(1) {
+        Some("200") => Ok(stream),
+        _ => Err(io::Error::other(format!("proxy refused CONNECT: {}", status_line))),
+    }
+}
+
+fn invalid_proxy(proxy: &str, reason: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("invalid proxy url {:?}: {}", proxy, reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proxy_port_defaults_by_scheme() {
+        let port = |proxy: &str| proxy_port(&proxy.parse().unwrap());
+        assert_eq!(port("http://proxy.local"), 80);
+        assert_eq!(port("socks5://proxy.local"), 1080);
+        assert_eq!(port("socks5h://proxy.local"), 1080);
+        assert_eq!(port("http://proxy.local:3128"), 3128);
+        assert_eq!(port("socks5://proxy.local:9050"), 9050);
+    }
+}
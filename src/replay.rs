@@ -0,0 +1,300 @@
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::future::Future;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use futures_util::{Sink, Stream};
+use serde::{Deserialize, Serialize};
+use tokio::time::Sleep;
+use tokio_tungstenite::tungstenite::{self, Message};
+use tracing::warn;
+use crate::config::Config;
+use crate::transport::{MockTransport, WsConnection, WsTransport};
+
+/// One received text frame, as written by `Recorder`: a JSON object per line
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedFrame {
+    pub received_at: u64, // unix ms
+    pub url: String,      // the connection it arrived on, to tell the subscription and gateway apart on replay
+    pub text: String,
+}
+
+/// Appends every received text frame to a file, see `Config::record_to`.  Clones share the file.
+/// Frames are handed to a writer thread, so recording never blocks the connection reading them.
+#[derive(Debug, Clone)]
+pub struct Recorder {
+    writer: Sender<WriterCommand>,
+}
+
+impl Recorder {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let (writer, commands) = mpsc::channel();
+        std::thread::Builder::new()
+            .name("vertex-recorder".to_string())
+            .spawn(move || write_frames(file, commands))?;
+        Ok(Recorder { writer })
+    }
+
+    pub fn record(&self, url: &str, text: &str) -> io::Result<()> {
+        let frame = RecordedFrame {
+            received_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("Time went backwards")
+                .as_millis() as u64,
+            url: url.to_string(),
+            text: text.to_string(),
+        };
+        let mut line = serde_json::to_string(&frame)?;
+        line.push('\n');
+        self.writer.send(WriterCommand::Line(line)).map_err(|_| writer_stopped())
+    }
+
+    /// Blocks until every frame recorded so far is written to the file
+    pub fn flush(&self) -> io::Result<()> {
+        let (done, flushed) = mpsc::sync_channel(1);
+        self.writer.send(WriterCommand::Flush(done)).map_err(|_| writer_stopped())?;
+        flushed.recv().map_err(|_| writer_stopped())?
+    }
+}
+
+#[derive(Debug)]
+enum WriterCommand {
+    Line(String), // one whole line per frame, so concurrent connections don't interleave
+    Flush(mpsc::SyncSender<io::Result<()>>),
+}
+
+fn writer_stopped() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "recording writer stopped")
+}
+
+// Runs on the recorder's thread until every `Recorder` is dropped.  Lines are buffered and flushed whenever the
+// queue runs dry, so the file stays current without a write per frame.
+fn write_frames(file: File, commands: Receiver<WriterCommand>) {
+    let mut file = BufWriter::new(file);
+    while let Ok(command) = commands.recv() {
+        let mut next = Some(command);
+        while let Some(command) = next {
+            match command {
+                WriterCommand::Line(line) => {
+                    if let Err(e) = file.write_all(line.as_bytes()) {
+                        warn!(error = %e, "failed to record frame");
+                    }
+                }
+                WriterCommand::Flush(done) => {
+                    let _ = done.send(file.flush());
+                }
+            }
+            next = commands.try_recv().ok();
+        }
+        if let Err(e) = file.flush() {
+            warn!(error = %e, "failed to write recording");
+        }
+    }
+}
+
+/// A connection whose inbound text frames are recorded when a `Recorder` is set; otherwise a plain passthrough
+#[derive(Debug)]
+pub struct Recorded<C> {
+    inner: C,
+    url: String,
+    recorder: Option<Recorder>,
+}
+
+impl<C> Recorded<C> {
+    pub fn new(inner: C, url: &str, recorder: Option<Recorder>) -> Self {
+        Recorded { inner, url: url.to_string(), recorder }
+    }
+}
+
+impl<C: WsConnection> Stream for Recorded<C> {
+    type Item = Result<Message, tungstenite::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let item = ready!(Pin::new(&mut self.inner).poll_next(cx));
+        if let (Some(Ok(Message::Text(text))), Some(recorder)) = (&item, &self.recorder) {
+            if let Err(e) = recorder.record(&self.url, text) {
+                warn!(error = %e, "failed to record frame");
+            }
+        }
+        Poll::Ready(item)
+    }
+}
+
+impl<C: WsConnection> Sink<Message> for Recorded<C> {
+    type Error = tungstenite::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        Pin::new(&mut self.inner).start_send(item)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+/// Transports that play a recording back in place of the network
+pub struct Replay {
+    pub subscription: ReplayTransport, // for `subscribe_with_transport`
+    pub gateway: MockTransport,        // for `GatewayClient::with_transport`; replies are returned in recorded order
+}
+
+/// Load a recording made with `Config::record_to`.  Frames recorded from `config.subscription_url` are replayed
+/// on the subscription, paced by their recorded timestamps divided by `speed` (None replays as fast as possible).
+/// Frames from `config.gateway_url` answer the gateway queries in order, so replaying through the same
+/// `OrderBookBuilder` gets the same snapshots the live run did.  Their recorded request ids are dropped: the
+/// replaying client tags its queries with new ids, which a recorded reply would never echo.
+pub fn replay_from(path: impl AsRef<Path>, config: &Config, speed: Option<f64>) -> io::Result<Replay> {
+    let mut subscription = Vec::new();
+    let mut gateway = Vec::new();
+    for (number, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let frame: RecordedFrame = serde_json::from_str(&line)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", number + 1, e)))?;
+        if frame.url == config.subscription_url {
+            subscription.push(frame);
+        } else if frame.url == config.gateway_url {
+            gateway.push(Message::Text(without_request_id(frame.text)));
+        }
+    }
+
+    Ok(Replay {
+        subscription: ReplayTransport {
+            frames: Arc::new(Mutex::new(Some(subscription.into()))),
+            speed,
+        },
+        gateway: MockTransport::new(vec![gateway]),
+    })
+}
+
+// A gateway reply with its `id` removed; `GatewayClient` accepts replies that don't echo one
+fn without_request_id(text: String) -> String {
+    match serde_json::from_str::<serde_json::Value>(&text) {
+        Ok(serde_json::Value::Object(mut fields)) => match fields.remove("id") {
+            Some(_) => serde_json::Value::Object(fields).to_string(),
+            None => text,
+        },
+        _ => text,
+    }
+}
+
+/// Replays recorded subscription frames on the first connection; later connections fail, ending the replay
+#[derive(Debug, Clone)]
+pub struct ReplayTransport {
+    frames: Arc<Mutex<Option<VecDeque<RecordedFrame>>>>,
+    speed: Option<f64>,
+}
+
+impl WsTransport for ReplayTransport {
+    type Connection = ReplayConnection;
+
+    async fn connect(&self, _url: &str) -> Result<ReplayConnection, tungstenite::Error> {
+        match self.frames.lock().unwrap().take() {
+            Some(frames) => Ok(ReplayConnection { frames, speed: self.speed, last_received_at: None, delay: None }),
+            None => Err(tungstenite::Error::ConnectionClosed),
+        }
+    }
+}
+
+/// Yields recorded frames, waiting out the recorded gap between them (scaled by `speed`).
+/// Anything sent on it is discarded.
+pub struct ReplayConnection {
+    frames: VecDeque<RecordedFrame>,
+    speed: Option<f64>,
+    last_received_at: Option<u64>,
+    delay: Option<Pin<Box<Sleep>>>, // wait before yielding the front frame
+}
+
+impl ReplayConnection {
+    fn next_frame(&mut self) -> Option<Result<Message, tungstenite::Error>> {
+        let frame = self.frames.pop_front()?;
+        self.last_received_at = Some(frame.received_at);
+        Some(Ok(Message::Text(frame.text)))
+    }
+}
+
+impl Stream for ReplayConnection {
+    type Item = Result<Message, tungstenite::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(delay) = self.delay.as_mut() {
+            ready!(delay.as_mut().poll(cx));
+            self.delay = None;
+            return Poll::Ready(self.next_frame());
+        }
+
+        let gap = match (self.frames.front(), self.last_received_at, self.speed) {
+            (Some(frame), Some(last), Some(speed)) if speed > 0.0 => {
+                Duration::from_millis(frame.received_at.saturating_sub(last)).div_f64(speed)
+            }
+            _ => Duration::ZERO,
+        };
+        if gap.is_zero() {
+            return Poll::Ready(self.next_frame()); // None once the recording is exhausted: the socket closed
+        }
+        self.delay = Some(Box::pin(tokio::time::sleep(gap)));
+        self.poll_next(cx)
+    }
+}
+
+impl Sink<Message> for ReplayConnection {
+    type Error = tungstenite::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, _item: Message) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listener::GatewayClient;
+    use crate::model::ProductId;
+
+    #[tokio::test]
+    async fn replayed_replies_answer_queries_with_new_ids() {
+        let path = std::env::temp_dir().join(format!("vertex-replay-{}.ndjson", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config = Config::default();
+        let recorder = Recorder::open(&path).unwrap();
+        // id 0 is never handed out again, like the ids of a previous run
+        let reply = r#"{"status":"success","data":{"bids":[["99","1"]],"asks":[["101","1"]],"timestamp":"1"},"request_type":"query_market_liquidity","id":0}"#;
+        recorder.record(&config.gateway_url, reply).unwrap();
+        recorder.flush().unwrap();
+
+        let replay = replay_from(&path, &config, None).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut gateway = GatewayClient::with_transport(replay.gateway, "ws://gateway", config.backoff, 0, Duration::from_secs(1), 0.0);
+        let snapshot = gateway.query_market_liquidity(ProductId(2), 10).await.unwrap();
+        assert_eq!(snapshot.data.timestamp, "1");
+        assert_eq!(snapshot.data.bids, vec![(99, 1)]);
+    }
+}
@@ -0,0 +1,107 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use crate::model::{ProductId, Subaccount};
+
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A process-wide unique request id, so replies (which echo it) can be matched to their requests
+pub fn next_request_id() -> u64 {
+    NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// A `subscribe` frame, e.g. `{"method":"subscribe","stream":{"type":"book_depth","product_id":2},"id":7}`.
+/// Each one gets its own id from `next_request_id`.
+#[derive(Debug, Clone, Serialize)]
+pub struct SubscriptionRequest {
+    method: &'static str,
+    stream: StreamSpec,
+    id: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct StreamSpec {
+    #[serde(rename = "type")]
+    stream_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    product_id: Option<ProductId>,
+    #[serde(flatten)]
+    params: Map<String, Value>, // stream-specific fields, e.g. subaccount or granularity
+}
+
+impl SubscriptionRequest {
+    pub fn new(stream_type: &str) -> Self {
+        SubscriptionRequest {
+            method: "subscribe",
+            stream: StreamSpec {
+                stream_type: stream_type.to_string(),
+                product_id: None,
+                params: Map::new(),
+            },
+            id: next_request_id(),
+        }
+    }
+
+    pub fn product_id(mut self, product_id: ProductId) -> Self {
+        self.stream.product_id = Some(product_id);
+        self
+    }
+
+    /// Any other stream field
+    pub fn param(mut self, key: &str, value: impl Serialize) -> Self {
+        let value = serde_json::to_value(value).expect("stream params serialize to json");
+        self.stream.params.insert(key.to_string(), value);
+        self
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("subscription request serializes to json")
+    }
+
+    pub fn book_depth(product_id: ProductId) -> Self {
+        SubscriptionRequest::new("book_depth").product_id(product_id)
+    }
+
+    pub fn trade(product_id: ProductId) -> Self {
+        SubscriptionRequest::new("trade").product_id(product_id)
+    }
+
+    pub fn best_bid_offer(product_id: ProductId) -> Self {
+        SubscriptionRequest::new("best_bid_offer").product_id(product_id)
+    }
+
+    pub fn market_price(product_id: ProductId) -> Self {
+        SubscriptionRequest::new("market_price").product_id(product_id)
+    }
+
+    /// `granularity` is the candle width in seconds, e.g. 60, 900, 3600, 86400
+    pub fn candlestick(product_id: ProductId, granularity: u32) -> Self {
+        SubscriptionRequest::new("latest_candlestick")
+            .product_id(product_id)
+            .param("granularity", granularity)
+    }
+
+    pub fn liquidation(product_id: ProductId) -> Self {
+        SubscriptionRequest::new("liquidation").product_id(product_id)
+    }
+
+    /// Requires an authenticated connection, see `StreamCredentials`
+    pub fn fill(product_id: ProductId, subaccount: Subaccount) -> Self {
+        SubscriptionRequest::new("fill").product_id(product_id).param("subaccount", subaccount)
+    }
+
+    /// Requires an authenticated connection, see `StreamCredentials`
+    pub fn position_change(product_id: ProductId, subaccount: Subaccount) -> Self {
+        SubscriptionRequest::new("position_change").product_id(product_id).param("subaccount", subaccount)
+    }
+}
+
+impl From<SubscriptionRequest> for String {
+    fn from(request: SubscriptionRequest) -> Self {
+        request.to_json()
+    }
+}
@@ -0,0 +1,135 @@
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio_stream::wrappers::ReceiverStream;
+use crate::model::{
+    BestBidOfferResponse, BookDepthResponse, CandlestickResponse, FillResponse, LiquidationResponse,
+    MarketPriceResponse, PositionChangeResponse, StreamResponseType, TradeResponse,
+};
+
+/// Fans the single event channel `Subscribe` writes to out into one typed stream per event type,
+/// so a consumer can `select!` over just the events it cares about.
+///
+/// Take the streams you need, then drive the router with `run` (e.g. `tokio::spawn(router.run())`).
+/// Events of a type nobody took a stream for are dropped.  Each stream is fed in turn, so one that isn't
+/// read holds the others back once its `buffer` fills, just like the underlying channel.
+pub struct StreamRouter {
+    receiver: Receiver<StreamResponseType>,
+    buffer: usize,
+    book_depth: Option<Sender<BookDepthResponse>>,
+    trade: Option<Sender<TradeResponse>>,
+    best_bid_offer: Option<Sender<BestBidOfferResponse>>,
+    fill: Option<Sender<FillResponse>>,
+    position_change: Option<Sender<PositionChangeResponse>>,
+    market_price: Option<Sender<MarketPriceResponse>>,
+    candlestick: Option<Sender<CandlestickResponse>>,
+    liquidation: Option<Sender<LiquidationResponse>>,
+    other: Option<Sender<StreamResponseType>>,
+}
+
+// A fresh channel for `slot`, replacing (and so ending) any stream previously taken for it
+fn take_stream<T>(slot: &mut Option<Sender<T>>, buffer: usize) -> ReceiverStream<T> {
+    let (sender, receiver) = mpsc::channel(buffer);
+    *slot = Some(sender);
+    ReceiverStream::new(receiver)
+}
+
+// Send to `slot` if anyone is listening; a dropped stream stops being fed
+async fn forward<T>(slot: &mut Option<Sender<T>>, event: T) {
+    if let Some(sender) = slot {
+        if sender.send(event).await.is_err() {
+            *slot = None;
+        }
+    }
+}
+
+impl StreamRouter {
+    /// `buffer` is the capacity of each typed stream
+    pub fn new(receiver: Receiver<StreamResponseType>, buffer: usize) -> Self {
+        StreamRouter {
+            receiver,
+            buffer,
+            book_depth: None,
+            trade: None,
+            best_bid_offer: None,
+            fill: None,
+            position_change: None,
+            market_price: None,
+            candlestick: None,
+            liquidation: None,
+            other: None,
+        }
+    }
+
+    pub fn book_depth_stream(&mut self) -> ReceiverStream<BookDepthResponse> {
+        take_stream(&mut self.book_depth, self.buffer)
+    }
+
+    pub fn trade_stream(&mut self) -> ReceiverStream<TradeResponse> {
+        take_stream(&mut self.trade, self.buffer)
+    }
+
+    pub fn best_bid_offer_stream(&mut self) -> ReceiverStream<BestBidOfferResponse> {
+        take_stream(&mut self.best_bid_offer, self.buffer)
+    }
+
+    pub fn fill_stream(&mut self) -> ReceiverStream<FillResponse> {
+        take_stream(&mut self.fill, self.buffer)
+    }
+
+    pub fn position_change_stream(&mut self) -> ReceiverStream<PositionChangeResponse> {
+        take_stream(&mut self.position_change, self.buffer)
+    }
+
+    pub fn market_price_stream(&mut self) -> ReceiverStream<MarketPriceResponse> {
+        take_stream(&mut self.market_price, self.buffer)
+    }
+
+    pub fn candlestick_stream(&mut self) -> ReceiverStream<CandlestickResponse> {
+        take_stream(&mut self.candlestick, self.buffer)
+    }
+
+    pub fn liquidation_stream(&mut self) -> ReceiverStream<LiquidationResponse> {
+        take_stream(&mut self.liquidation, self.buffer)
+    }
+
+    /// Everything that isn't a market event: subscription acks, `Reconnected` and unrecognised events.
+    /// Watch it for `Reconnected` to know when typed streams may have missed events.
+    pub fn other_stream(&mut self) -> ReceiverStream<StreamResponseType> {
+        take_stream(&mut self.other, self.buffer)
+    }
+
+    /// Route events until the subscription ends, which ends every stream.  Also returns once every taken stream
+    /// has been dropped (noticed on the next event), dropping the receiver so `Subscribe` stops.
+    pub async fn run(mut self) {
+        while let Some(event) = self.receiver.recv().await {
+            match event {
+                StreamResponseType::BookDepth(data) => forward(&mut self.book_depth, data).await,
+                StreamResponseType::Trade(data) => forward(&mut self.trade, data).await,
+                StreamResponseType::BestBidOffer(data) => forward(&mut self.best_bid_offer, data).await,
+                StreamResponseType::Fill(data) => forward(&mut self.fill, data).await,
+                StreamResponseType::PositionChange(data) => forward(&mut self.position_change, data).await,
+                StreamResponseType::MarketPrice(data) => forward(&mut self.market_price, data).await,
+                StreamResponseType::Candlestick(data) => forward(&mut self.candlestick, data).await,
+                StreamResponseType::Liquidation(data) => forward(&mut self.liquidation, data).await,
+                event @ (StreamResponseType::SubscriptionResponse(_)
+                | StreamResponseType::Reconnected
+                | StreamResponseType::Unknown) => forward(&mut self.other, event).await,
+            }
+            if !self.is_routing() {
+                return;
+            }
+        }
+    }
+
+    // Whether any taken stream is still being read
+    fn is_routing(&self) -> bool {
+        self.book_depth.is_some()
+            || self.trade.is_some()
+            || self.best_bid_offer.is_some()
+            || self.fill.is_some()
+            || self.position_change.is_some()
+            || self.market_price.is_some()
+            || self.candlestick.is_some()
+            || self.liquidation.is_some()
+            || self.other.is_some()
+    }
+}
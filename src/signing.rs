@@ -0,0 +1,98 @@
+use ethers::prelude::LocalWallet;
+use ethers::types::H256;
+use ethers_core::types::transaction::eip712::{EIP712Domain, Eip712};
+use ethers_core::utils::keccak256;
+
+/// keccak256(0x19 0x01 || domain separator || struct hash): the EIP712 digest of `value` under `domain`.
+/// `domain` is passed in rather than taken from `value`, since Vertex signs one struct under several domains
+/// (see `Network::eip712_domain` and `Network::order_domain`).
+pub fn eip712_digest<T: Eip712>(value: &T, domain: &EIP712Domain) -> Result<H256, String> {
+    digest(value, &domain.separator())
+}
+
+/// Sign `value` under `domain`, as the `0x`-prefixed hex signature the gateway expects
+pub fn sign_eip712<T: Eip712>(value: &T, domain: &EIP712Domain, wallet: &LocalWallet) -> Result<String, String> {
+    sign(value, &domain.separator(), wallet)
+}
+
+/// An EIP712 domain with its separator computed up front, for signing many structs under the same domain
+#[derive(Debug, Clone)]
+pub struct SigningDomain {
+    domain: EIP712Domain,
+    separator: [u8; 32],
+}
+
+impl SigningDomain {
+    pub fn new(domain: EIP712Domain) -> Self {
+        let separator = domain.separator();
+        SigningDomain { domain, separator }
+    }
+
+    pub fn domain(&self) -> &EIP712Domain {
+        &self.domain
+    }
+
+    pub fn separator(&self) -> [u8; 32] {
+        self.separator
+    }
+
+    /// Same as `eip712_digest` under this domain
+    pub fn digest<T: Eip712>(&self, value: &T) -> Result<H256, String> {
+        digest(value, &self.separator)
+    }
+
+    /// Same as `sign_eip712` under this domain
+    pub fn sign<T: Eip712>(&self, value: &T, wallet: &LocalWallet) -> Result<String, String> {
+        sign(value, &self.separator, wallet)
+    }
+}
+
+fn digest<T: Eip712>(value: &T, domain_separator: &[u8; 32]) -> Result<H256, String> {
+    let struct_hash = value.struct_hash().map_err(|e| e.to_string())?;
+    let digest_input = [&[0x19, 0x01], &domain_separator[..], &struct_hash[..]].concat();
+    Ok(H256::from(keccak256(digest_input)))
+}
+
+fn sign<T: Eip712>(value: &T, domain_separator: &[u8; 32], wallet: &LocalWallet) -> Result<String, String> {
+    let digest_hash = digest(value, domain_separator)?;
+    let signature = wallet.sign_hash(digest_hash).map_err(|e| e.to_string())?;
+    Ok(format!("0x{}", signature))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::Signature;
+    use ethers_signers::Signer;
+    use vertex_sdk::eip712_structs::StreamAuthentication;
+    use crate::config::Network;
+    use crate::listener::{build_sender, encode_subaccount};
+
+    // Hardhat's first dev account: a well-known key, never funded on a real network
+    const PRIVATE_KEY: &str = "ac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80";
+
+    fn stream_authentication(wallet: &LocalWallet) -> StreamAuthentication {
+        let sender = build_sender(wallet.address(), encode_subaccount("default").unwrap());
+        StreamAuthentication { sender: sender.0, expiration: 1_700_000_000_000 }
+    }
+
+    #[test]
+    fn stream_authentication_known_answer() {
+        let wallet: LocalWallet = PRIVATE_KEY.parse().unwrap();
+        let auth = stream_authentication(&wallet);
+        let domain = Network::Mainnet.eip712_domain();
+
+        let digest = eip712_digest(&auth, &domain).unwrap();
+        assert_eq!(format!("{:?}", digest), "0xa04be113b23ce8c98df6a3b9c879e7ec0fc57760526196f5a2a7e0db7d961578");
+        let signature = sign_eip712(&auth, &domain, &wallet).unwrap();
+        assert_eq!(
+            signature,
+            "0xd2086b3014a769cad86d4d1ff2e09d486fadf35d207de6983fa021f5c7a8431b\
+             527ef25b23b0abe926a9df76a831a882b1849bed16f935380e7a802a0dbfc3641c"
+        );
+
+        let recovered = signature[2..].parse::<Signature>().unwrap().recover(digest).unwrap();
+        assert_eq!(recovered, wallet.address());
+        assert_eq!(SigningDomain::new(domain).sign(&auth, &wallet).unwrap(), signature);
+    }
+}
@@ -0,0 +1,136 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Throughput counters shared between the listener and the order book builder.
+/// Plain relaxed atomics, so incrementing on the hot path never allocates or locks.
+#[derive(Debug, Default)]
+pub struct Stats {
+    updates_applied: AtomicU64,
+    updates_dropped: AtomicU64, // stale events from before the snapshot
+    gaps_detected: AtomicU64,
+    resnapshots: AtomicU64,
+    parse_errors: AtomicU64,
+    last_message_at: AtomicU64, // unix ms of the last inbound frame on the subscription, 0 if none yet
+    reconnects: AtomicU64,        // connections established after the first, never reset
+    last_reconnect_at: AtomicU64, // unix ms, 0 if the subscription hasn't reconnected yet
+    connected_at: AtomicU64,      // unix ms the current connection was established, 0 while disconnected
+}
+
+/// Point-in-time copy of `Stats`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    pub updates_applied: u64,
+    pub updates_dropped: u64,
+    pub gaps_detected: u64,
+    pub resnapshots: u64,
+    pub parse_errors: u64,
+}
+
+impl Stats {
+    pub fn record_update_applied(&self) {
+        self.updates_applied.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_update_dropped(&self) {
+        self.updates_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_gap(&self) {
+        self.gaps_detected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_resnapshot(&self) {
+        self.resnapshots.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_parse_error(&self) {
+        self.parse_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Any inbound frame on the subscription, pongs included, proves the feed is alive
+    pub fn record_message(&self) {
+        self.last_message_at.store(unix_millis(SystemTime::now()), Ordering::Relaxed);
+    }
+
+    /// When the subscription last received a frame.  None if it hasn't yet.
+    /// Unlike the counters this is a gauge: `take` doesn't reset it.
+    pub fn last_message_at(&self) -> Option<SystemTime> {
+        match self.last_message_at.load(Ordering::Relaxed) {
+            0 => None,
+            millis => Some(UNIX_EPOCH + Duration::from_millis(millis)),
+        }
+    }
+
+    /// Time since the subscription last received a frame, e.g. for a health check to report the feed
+    /// as stale past some threshold.  None if no frame has arrived yet.
+    pub fn last_message_age(&self) -> Option<Duration> {
+        self.last_message_at()
+            .map(|at| SystemTime::now().duration_since(at).unwrap_or_default()) // clock stepped back: just received
+    }
+
+    /// The subscription's socket is up.  `reconnect` if it replaces an earlier connection.
+    pub fn record_connected(&self, reconnect: bool) {
+        let now = unix_millis(SystemTime::now());
+        self.connected_at.store(now, Ordering::Relaxed);
+        if reconnect {
+            self.reconnects.fetch_add(1, Ordering::Relaxed);
+            self.last_reconnect_at.store(now, Ordering::Relaxed);
+        }
+    }
+
+    /// The subscription's socket went down
+    pub fn record_disconnected(&self) {
+        self.connected_at.store(0, Ordering::Relaxed);
+    }
+
+    /// How many times the subscription has reconnected since it started.  A running total: `take` doesn't reset it.
+    pub fn total_reconnects(&self) -> u64 {
+        self.reconnects.load(Ordering::Relaxed)
+    }
+
+    /// When the subscription last reconnected.  None if it is still on its first connection.
+    pub fn last_reconnect_at(&self) -> Option<SystemTime> {
+        match self.last_reconnect_at.load(Ordering::Relaxed) {
+            0 => None,
+            millis => Some(UNIX_EPOCH + Duration::from_millis(millis)),
+        }
+    }
+
+    /// How long the current connection has been up.  None while disconnected.
+    pub fn current_uptime(&self) -> Option<Duration> {
+        match self.connected_at.load(Ordering::Relaxed) {
+            0 => None,
+            millis => Some(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH + Duration::from_millis(millis))
+                    .unwrap_or_default(), // clock stepped back: just connected
+            ),
+        }
+    }
+
+    /// Read the counters
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            updates_applied: self.updates_applied.load(Ordering::Relaxed),
+            updates_dropped: self.updates_dropped.load(Ordering::Relaxed),
+            gaps_detected: self.gaps_detected.load(Ordering::Relaxed),
+            resnapshots: self.resnapshots.load(Ordering::Relaxed),
+            parse_errors: self.parse_errors.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Read the counters and reset them to zero, e.g. once per scrape interval
+    pub fn take(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            updates_applied: self.updates_applied.swap(0, Ordering::Relaxed),
+            updates_dropped: self.updates_dropped.swap(0, Ordering::Relaxed),
+            gaps_detected: self.gaps_detected.swap(0, Ordering::Relaxed),
+            resnapshots: self.resnapshots.swap(0, Ordering::Relaxed),
+            parse_errors: self.parse_errors.swap(0, Ordering::Relaxed),
+        }
+    }
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).expect("Time went backwards").as_millis() as u64
+}
@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+use crate::config::Network;
+use crate::model::ProductId;
+
+// Known mainnet products.  Spot markets are `<BASE>-USDC`, perps `<BASE>-USDC-PERP`; product 0 is the USDC quote asset.
+const MAINNET_PRODUCTS: &[(u32, &str)] = &[
+    (1, "BTC-USDC"),
+    (2, "BTC-USDC-PERP"),
+    (3, "ETH-USDC"),
+    (4, "ETH-USDC-PERP"),
+    (5, "ARB-USDC"),
+    (6, "ARB-USDC-PERP"),
+    (8, "BNB-USDC-PERP"),
+    (10, "XRP-USDC-PERP"),
+    (12, "SOL-USDC-PERP"),
+    (14, "MATIC-USDC-PERP"),
+    (16, "SUI-USDC-PERP"),
+    (18, "OP-USDC-PERP"),
+    (20, "APT-USDC-PERP"),
+    (22, "LTC-USDC-PERP"),
+    (24, "BCH-USDC-PERP"),
+    (26, "COMP-USDC-PERP"),
+    (28, "MKR-USDC-PERP"),
+    (34, "DOGE-USDC-PERP"),
+    (36, "LINK-USDC-PERP"),
+];
+
+/// Two-way map between product ids and human-readable symbols like "BTC-USDC-PERP".
+/// Seeded from the known mainnet products; use `insert` to override or extend it (e.g. for testnet or newly listed markets).
+#[derive(Debug, Clone)]
+pub struct SymbolRegistry {
+    symbols: HashMap<ProductId, String>,
+    products: HashMap<String, ProductId>, // keyed by upper-cased symbol
+}
+
+impl Default for SymbolRegistry {
+    fn default() -> Self {
+        SymbolRegistry::mainnet()
+    }
+}
+
+impl SymbolRegistry {
+    /// An empty registry
+    pub fn new() -> Self {
+        SymbolRegistry {
+            symbols: HashMap::new(),
+            products: HashMap::new(),
+        }
+    }
+
+    pub fn mainnet() -> Self {
+        let mut registry = SymbolRegistry::new();
+        for (product_id, symbol) in MAINNET_PRODUCTS {
+            registry.insert(ProductId(*product_id), symbol);
+        }
+        registry
+    }
+
+    /// The known products of `network`.  Testnet product ids differ from mainnet's and aren't listed here,
+    /// so its registry starts empty: `insert` the products you use.
+    pub fn for_network(network: Network) -> Self {
+        match network {
+            Network::Mainnet => SymbolRegistry::mainnet(),
+            Network::Testnet => SymbolRegistry::new(),
+        }
+    }
+
+    /// Map `product_id` to `symbol`, replacing any previous mapping of either
+    pub fn insert(&mut self, product_id: ProductId, symbol: &str) {
+        if let Some(old_symbol) = self.symbols.remove(&product_id) {
+            self.products.remove(&old_symbol.to_uppercase());
+        }
+        if let Some(old_product) = self.products.remove(&symbol.to_uppercase()) {
+            self.symbols.remove(&old_product);
+        }
+        self.symbols.insert(product_id, symbol.to_string());
+        self.products.insert(symbol.to_uppercase(), product_id);
+    }
+
+    pub fn symbol_for(&self, product_id: ProductId) -> Option<&str> {
+        self.symbols.get(&product_id).map(String::as_str)
+    }
+
+    /// Case-insensitive
+    pub fn product_for(&self, symbol: &str) -> Option<ProductId> {
+        self.products.get(&symbol.to_uppercase()).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mainnet_symbols_are_not_used_for_testnet() {
+        assert_eq!(SymbolRegistry::for_network(Network::Mainnet).symbol_for(ProductId(2)), Some("BTC-USDC-PERP"));
+        assert_eq!(SymbolRegistry::for_network(Network::Testnet).symbol_for(ProductId(2)), None);
+    }
+}
@@ -0,0 +1,153 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use futures_util::{Sink, Stream};
+use tokio_tungstenite::tungstenite::{self, protocol::WebSocketConfig, Message};
+use tokio_tungstenite::tungstenite::extensions::DeflateConfig;
+use tracing::warn;
+use crate::config::Config;
+use crate::listener::WsStream;
+use crate::proxy;
+use crate::replay::{Recorded, Recorder};
+
+/// An open websocket: a stream of inbound frames and a sink for outbound ones
+pub trait WsConnection:
+    Stream<Item = Result<Message, tungstenite::Error>> + Sink<Message, Error = tungstenite::Error> + Unpin + Send
+{
+}
+
+impl<T> WsConnection for T where
+    T: Stream<Item = Result<Message, tungstenite::Error>> + Sink<Message, Error = tungstenite::Error> + Unpin + Send
+{
+}
+
+/// Opens websocket connections.  `Subscribe` and `GatewayClient` connect through this,
+/// so tests can swap the network for scripted frames (see `MockTransport`).
+pub trait WsTransport: Send + Sync {
+    type Connection: WsConnection;
+
+    fn connect(&self, url: &str) -> impl Future<Output = Result<Self::Connection, tungstenite::Error>> + Send;
+}
+
+/// The real transport: tokio-tungstenite, optionally compressed, proxied and/or recorded
+#[derive(Debug, Clone, Default)]
+pub struct TungsteniteTransport {
+    pub compression: Option<DeflateConfig>, // permessage-deflate is negotiated only when set
+    pub proxy_url: Option<String>,
+    pub recorder: Option<Recorder>, // received text frames are appended here, see `replay_from`
+    pub bind_address: Option<IpAddr>, // local address outbound connections originate from
+}
+
+impl TungsteniteTransport {
+    pub fn from_config(config: &Config) -> Self {
+        // a recording is a debugging aid: carry on without one rather than refuse to connect
+        let recorder = config.record_to.as_ref().and_then(|path| match Recorder::open(path) {
+            Ok(recorder) => Some(recorder),
+            Err(e) => {
+                warn!(error = %e, path = %path, "unable to open recording, not recording");
+                None
+            }
+        });
+        TungsteniteTransport {
+            compression: config.compression,
+            proxy_url: config.proxy_url.clone(),
+            recorder,
+            bind_address: config.bind_address,
+        }
+    }
+}
+
+impl WsTransport for TungsteniteTransport {
+    type Connection = Recorded<WsStream>;
+
+    async fn connect(&self, url: &str) -> Result<Recorded<WsStream>, tungstenite::Error> {
+        let config = WebSocketConfig {
+            compression: self.compression,
+            ..WebSocketConfig::default()
+        };
+        let (ws, _) = proxy::connect(url, config, self.proxy_url.as_deref(), self.bind_address).await?;
+        Ok(Recorded::new(ws, url, self.recorder.clone()))
+    }
+}
+
+/// Transport for tests.  Each `connect` consumes the next script: the frames that connection will yield,
+/// in order, before it reports the socket closed.  Once the scripts run out, connecting fails.
+/// Everything sent on any connection is recorded in `sent`.
+#[derive(Debug, Clone, Default)]
+pub struct MockTransport {
+    scripts: Arc<Mutex<VecDeque<Vec<Message>>>>,
+    pub sent: Arc<Mutex<Vec<Message>>>,
+}
+
+impl MockTransport {
+    pub fn new(scripts: Vec<Vec<Message>>) -> Self {
+        MockTransport {
+            scripts: Arc::new(Mutex::new(scripts.into())),
+            sent: Arc::default(),
+        }
+    }
+
+    /// Convenience for scripts of text frames
+    pub fn from_text(scripts: Vec<Vec<&str>>) -> Self {
+        MockTransport::new(
+            scripts
+                .into_iter()
+                .map(|frames| frames.into_iter().map(|frame| Message::Text(frame.to_string())).collect())
+                .collect(),
+        )
+    }
+
+    pub fn sent(&self) -> Vec<Message> {
+        self.sent.lock().unwrap().clone()
+    }
+}
+
+impl WsTransport for MockTransport {
+    type Connection = MockConnection;
+
+    async fn connect(&self, _url: &str) -> Result<MockConnection, tungstenite::Error> {
+        match self.scripts.lock().unwrap().pop_front() {
+            Some(frames) => Ok(MockConnection { incoming: frames.into(), sent: self.sent.clone() }),
+            None => Err(tungstenite::Error::ConnectionClosed),
+        }
+    }
+}
+
+/// A scripted connection from `MockTransport`
+#[derive(Debug)]
+pub struct MockConnection {
+    incoming: VecDeque<Message>,
+    sent: Arc<Mutex<Vec<Message>>>,
+}
+
+impl Stream for MockConnection {
+    type Item = Result<Message, tungstenite::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Poll::Ready(self.incoming.pop_front().map(Ok)) // None once the script is exhausted: the socket closed
+    }
+}
+
+impl Sink<Message> for MockConnection {
+    type Error = tungstenite::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        self.sent.lock().unwrap().push(item);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+}